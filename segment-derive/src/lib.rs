@@ -2,13 +2,11 @@
 extern crate proc_macro;
 extern crate proc_macro2;
 
-use std::fmt;
-
 use proc_macro::TokenStream;
-use syn::{parse_macro_input, DeriveInput, Data, Fields, Field, LitStr, Lit, Ident};
-use syn::Meta::{List, NameValue, Word};
-use syn::NestedMeta::Meta;
-use quote::quote;
+use syn::{parse_macro_input, DeriveInput, Data, Fields, Field, Generics, LitStr, Ident};
+use syn::meta::ParseNestedMeta;
+use syn::spanned::Spanned;
+use quote::{quote, quote_spanned};
 
 
 /// The type of line protocol element a field represents.
@@ -17,20 +15,10 @@ enum SegmentFieldType {
     Tag,
     Field,
     Time,
-}
-
-#[derive(Debug, Clone)]
-enum MetricError {
-    NoFields(),
-}
-
-impl fmt::Display for MetricError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let s = match self {
-            MetricError::NoFields() => "no fields defined for metric",
-        };
-        write!(f, "{}", s)
-    }
+    Measurement,
+    Flatten,
+    TagMap,
+    FieldMap,
 }
 
 struct SegmentField {
@@ -40,210 +28,980 @@ struct SegmentField {
     name: String,
     // What type of value in the line proto is this
     field_type: SegmentFieldType,
+    // Whether unsigned integers are always written with the `u` suffix
+    unsigned: bool,
+    // Variant of `segment::Precision` used for the time member
+    precision: Option<Ident>,
+    // Whether the value is written through its `Display` implementation
+    display: bool,
+    // Predicate, given a reference to the member, omitting it when true
+    skip_if: Option<syn::ExprPath>,
+    // Value of the member when decoding a line that lacks it
+    default: Option<MemberDefault>,
+}
+
+/// The value given to a member missing from a decoded line.
+enum MemberDefault {
+    // `#[segment(default)]`
+    Trait,
+    // `#[segment(default = "path")]`
+    Path(syn::ExprPath),
+}
+
+impl SegmentField {
+    /// A reference to the value written for this member, spanned to its type
+    /// so that a missing trait implementation is reported on the member.
+    fn value_ref(&self) -> proc_macro2::TokenStream {
+        let v = &self.struct_field.ident;
+        let span = self.struct_field.ty.span();
+        match (self.display, option_inner(&self.struct_field.ty).is_some()) {
+            (false, _) => quote_spanned!(span=> &self.#v),
+            (true, false) => quote_spanned!(span=> &segment::Displayed(&self.#v)),
+            (true, true) => quote_spanned!(span=> &self.#v.as_ref().map(segment::Displayed)),
+        }
+    }
+
+    /// Wraps `write` so that it only runs when the member is not skipped.
+    fn unless_skipped(&self, write: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+        match self.skip_if {
+            Some(ref skip_if) => {
+                let v = &self.struct_field.ident;
+                quote!{
+                    if !#skip_if(&self.#v) {
+                        #write
+                    }
+                }
+            },
+            None => write,
+        }
+    }
+
+    /// The value of the member, given the local `var` holding the value
+    /// decoded from the line, if any.
+    fn decoded_member(&self, var: &Ident, missing: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+        let optional = option_inner(&self.struct_field.ty).is_some();
+        let default = match self.default {
+            Some(MemberDefault::Trait) => quote!(::core::default::Default::default()),
+            Some(MemberDefault::Path(ref path)) => quote!(#path()),
+            // Optional members are `None` when absent, others are required.
+            None if optional => return quote!(#var),
+            None => return quote!(#var.ok_or_else(|| #missing)?),
+        };
+        if optional {
+            quote!(match #var { ::core::option::Option::Some(v) => ::core::option::Option::Some(v), ::core::option::Option::None => #default })
+        } else {
+            quote!(match #var { ::core::option::Option::Some(v) => v, ::core::option::Option::None => #default })
+        }
+    }
+
+    /// Converts `value`, decoded through `FromTagValue` or `FromFieldValue`,
+    /// into the member's value type.
+    fn decoded(&self, value: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+        if self.display {
+            let ty = value_type(&self.struct_field.ty).0;
+            quote!(#value.map(|segment::Displayed(v): segment::Displayed<#ty>| v))
+        } else {
+            value
+        }
+    }
+}
+
+/// A tag or field declared on the struct itself, with a constant value.
+struct ConstMember {
+    key: Ident,
+    value: ConstValue,
+}
+
+enum ConstValue {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl ConstMember {
+    fn name(&self) -> String {
+        self.key.to_string()
+    }
+
+    /// The unescaped value, as written for a tag.
+    fn tag_value(&self) -> String {
+        match self.value {
+            ConstValue::Str(ref v) => v.clone(),
+            ConstValue::Int(v) => v.to_string(),
+            ConstValue::Float(v) => format!("{:?}", v),
+            ConstValue::Bool(v) => v.to_string(),
+        }
+    }
+
+    /// The escaped `key=value` pair, as written for a field.
+    fn field_pair(&self) -> String {
+        let value = match self.value {
+            ConstValue::Str(ref v) => format!("\"{}\"", escape_static(v, &['"', '\\'])),
+            ConstValue::Int(v) => format!("{}i", v),
+            ConstValue::Float(v) => format!("{:?}", v),
+            ConstValue::Bool(v) => (if v { "t" } else { "f" }).to_string(),
+        };
+        format!("{}={}", escape_key(&self.name()), value)
+    }
+
+    /// A `segment::FieldValue` expression for the value of a field.
+    fn field_value(&self) -> proc_macro2::TokenStream {
+        match self.value {
+            ConstValue::Str(ref v) => quote!(segment::FieldValue::Str(#v.to_string())),
+            ConstValue::Int(v) => quote!(segment::FieldValue::Int64(#v)),
+            ConstValue::Float(v) => quote!(segment::FieldValue::Float64(#v)),
+            ConstValue::Bool(v) => quote!(segment::FieldValue::Bool(#v)),
+        }
+    }
+}
+
+/// A tag of the line, either held by a member or constant.
+enum TagEntry<'a> {
+    Member(&'a SegmentField),
+    Const(&'a ConstMember),
+}
+
+impl TagEntry<'_> {
+    fn name(&self) -> String {
+        match self {
+            TagEntry::Member(t) => t.name.clone(),
+            TagEntry::Const(c) => c.name(),
+        }
+    }
 }
 
 struct SegmentMetric {
     name: Ident,
+    generics: Generics,
     measurement: LitStr,
+    // Whether `measurement` was given, rather than taken from the struct name
+    explicit_measurement: bool,
+    // Function computing the measurement from the metric
+    measurement_path: Option<syn::ExprPath>,
+    // Member holding the measurement
+    measurement_field: Option<SegmentField>,
     fields: Vec<SegmentField>,
     tags: Vec<SegmentField>,
     time_field: Option<SegmentField>,
+    // Members whose own tags and fields are merged into the line
+    flattened: Vec<SegmentField>,
+    // Maps whose entries are written as tags
+    tag_maps: Vec<SegmentField>,
+    // Maps whose entries are written as fields
+    field_maps: Vec<SegmentField>,
+    // Tags and fields declared on the struct itself
+    const_tags: Vec<ConstMember>,
+    const_fields: Vec<ConstMember>,
+    // Members that take no part in the line protocol
+    others: Vec<SegmentField>,
 }
 
 impl SegmentMetric {
-    pub fn build(data: DeriveInput) -> Result<SegmentMetric, MetricError> {
-        let mut metric: SegmentMetric = SegmentMetric{
+    fn new(data: &DeriveInput) -> SegmentMetric {
+        SegmentMetric{
             name: data.ident.clone(),
+            generics: data.generics.clone(),
             measurement: syn::LitStr::new(&data.ident.to_string(), data.ident.span()),
+            explicit_measurement: false,
+            measurement_path: None,
+            measurement_field: None,
             fields: Vec::new(),
             tags: Vec::new(),
             time_field: None,
-        };
+            flattened: Vec::new(),
+            tag_maps: Vec::new(),
+            field_maps: Vec::new(),
+            const_tags: Vec::new(),
+            const_fields: Vec::new(),
+            others: Vec::new(),
+        }
+    }
+
+    pub fn build(data: DeriveInput) -> syn::Result<SegmentMetric> {
+        let mut metric = SegmentMetric::new(&data);
 
         // Get Measurement name if present..
-        for meta_item in data.attrs.iter().filter_map(get_segment_meta).flat_map(|x| x) {
-            match meta_item {
-                Meta(NameValue(ref m)) if m.ident == "measurement" =>
-                    if let syn::Lit::Str(ref lit) = m.lit {
-                        metric.measurement = lit.clone();
-                    },
-                _ => (),
+        for_each_segment_meta(&data.attrs, |meta| {
+            if meta.path.is_ident("measurement") {
+                metric.measurement = string_value(&meta, "measurement")?;
+                metric.explicit_measurement = true;
+                Ok(())
+            } else if meta.path.is_ident("measurement_fn") {
+                metric.measurement_path = Some(string_value(&meta, "measurement_fn")?.parse()?);
+                Ok(())
+            } else if metric.const_attr(&meta)? {
+                Ok(())
+            } else {
+                Err(meta.error("unknown segment attribute, expected one of `measurement = \"...\"`, `measurement_fn = \"...\"`, `tag(...)` or `field(...)`"))
             }
+        })?;
+        if metric.measurement.value().is_empty() {
+            return Err(syn::Error::new_spanned(&metric.measurement, "measurement name cannot be empty"));
+        }
+        if let (true, Some(path)) = (metric.explicit_measurement, &metric.measurement_path) {
+            return Err(syn::Error::new_spanned(path, "`measurement` and `measurement_fn` cannot both be used"));
         }
 
         // Gather all fields from the metric.
-        metric.process_fields(&data.data)?;
+        metric.process_fields(&data)?;
+        metric.check_consts()?;
+        if let Some(ref m) = metric.measurement_field {
+            if metric.explicit_measurement || metric.measurement_path.is_some() {
+                return Err(syn::Error::new_spanned(
+                    &m.struct_field,
+                    "the measurement is already given by the struct's `measurement` or `measurement_fn` attribute"));
+            }
+        }
 
-        if metric.fields.len() == 0 {
-            Err(MetricError::NoFields())
+        if metric.fields.is_empty() && metric.flattened.is_empty() && metric.field_maps.is_empty()
+            && metric.const_fields.is_empty() {
+            Err(syn::Error::new_spanned(
+                &data.ident,
+                "no fields defined for metric, mark at least one member with `#[segment(field)]`"))
         } else {
             Ok(metric)
         }
     }
 
+    /// Gathers the members of a struct deriving `Flatten`, a group of tags and
+    /// fields without a measurement or time of its own.
+    pub fn build_group(data: DeriveInput) -> syn::Result<SegmentMetric> {
+        let mut group = SegmentMetric::new(&data);
+
+        for_each_segment_meta(&data.attrs, |meta| {
+            if group.const_attr(&meta)? {
+                Ok(())
+            } else {
+                Err(meta.error("unknown segment attribute, expected `tag(...)` or `field(...)`"))
+            }
+        })?;
+        group.process_fields(&data)?;
+        group.check_consts()?;
+
+        if let Some(ref t) = group.time_field {
+            Err(syn::Error::new_spanned(
+                &t.struct_field,
+                "a flattened struct cannot have a time member, it belongs to the metric"))
+        } else if let Some(ref m) = group.measurement_field {
+            Err(syn::Error::new_spanned(
+                &m.struct_field,
+                "a flattened struct cannot have a measurement member, it belongs to the metric"))
+        } else if group.tags.is_empty() && group.fields.is_empty() && group.flattened.is_empty()
+            && group.tag_maps.is_empty() && group.field_maps.is_empty()
+            && group.const_tags.is_empty() && group.const_fields.is_empty() {
+            Err(syn::Error::new_spanned(
+                &data.ident,
+                "no tags or fields defined, mark at least one member with `#[segment(tag)]` or `#[segment(field)]`"))
+        } else {
+            Ok(group)
+        }
+    }
+
+    /// Parses `tag(key = value, ...)` or `field(key = value, ...)`, returning
+    /// `false` if `meta` is neither.
+    fn const_attr(&mut self, meta: &ParseNestedMeta) -> syn::Result<bool> {
+        let consts = if meta.path.is_ident("tag") {
+            &mut self.const_tags
+        } else if meta.path.is_ident("field") {
+            &mut self.const_fields
+        } else {
+            return Ok(false);
+        };
+        meta.parse_nested_meta(|entry| {
+            let key = match entry.path.get_ident() {
+                Some(key) => key.clone(),
+                None => return Err(entry.error("expected a key, as in `key = value`")),
+            };
+            let value = const_value(&entry.value()?.parse()?)?;
+            consts.push(ConstMember{ key, value });
+            Ok(())
+        })?;
+        Ok(true)
+    }
+
+    /// Rejects constant tags and fields whose keys are already used, and
+    /// constant tags with an empty value.
+    fn check_consts(&self) -> syn::Result<()> {
+        for (idx, c) in self.const_tags.iter().enumerate() {
+            if self.tags.iter().any(|t| t.name == c.name()) || self.const_tags[..idx].iter().any(|o| o.key == c.key) {
+                return Err(syn::Error::new_spanned(&c.key, format!("duplicate tag \"{}\"", c.key)));
+            }
+            if c.tag_value().is_empty() {
+                return Err(syn::Error::new_spanned(&c.key, "constant tag values cannot be empty"));
+            }
+        }
+        for (idx, c) in self.const_fields.iter().enumerate() {
+            if self.fields.iter().any(|f| f.name == c.name()) || self.const_fields[..idx].iter().any(|o| o.key == c.key) {
+                return Err(syn::Error::new_spanned(&c.key, format!("duplicate field \"{}\"", c.key)));
+            }
+        }
+        Ok(())
+    }
+
+    /// The tags held by members and the constant tags, sorted by key.
+    fn tag_entries(&self) -> Vec<TagEntry<'_>> {
+        let mut entries: Vec<TagEntry> = self.tags.iter().map(TagEntry::Member)
+            .chain(self.const_tags.iter().map(TagEntry::Const))
+            .collect();
+        entries.sort_by_key(|e| e.name());
+        entries
+    }
+
+    /// Keys of the tags declared on the struct, whether held by members or
+    /// constant.
+    fn tag_names(&self) -> Vec<String> {
+        self.tags.iter().map(|t| t.name.clone()).chain(self.const_tags.iter().map(ConstMember::name)).collect()
+    }
+
+    /// Keys of the fields declared on the struct, whether held by members or
+    /// constant.
+    fn field_names(&self) -> Vec<String> {
+        self.fields.iter().map(|f| f.name.clone()).chain(self.const_fields.iter().map(ConstMember::name)).collect()
+    }
+
     pub fn measurement_fn(&self) -> proc_macro2::TokenStream {
-        let measurement = &self.measurement;
-        quote!(fn measurement(&self) -> String { #measurement.to_string() })
+        match self.measurement_ref() {
+            Some(measurement) => quote!(fn measurement(&self) -> ::std::string::String {
+                #measurement
+                measurement.to_string()
+            }),
+            None => {
+                let measurement = &self.measurement;
+                quote!(fn measurement(&self) -> ::std::string::String { #measurement.to_string() })
+            },
+        }
+    }
+
+    /// For a measurement only known at runtime, the code binding it to
+    /// `measurement`, as a `&str`.
+    fn measurement_ref(&self) -> Option<proc_macro2::TokenStream> {
+        if let Some(ref m) = self.measurement_field {
+            let v = &m.struct_field.ident;
+            Some(quote_spanned!(m.struct_field.ty.span()=>
+                let measurement: &str = ::core::convert::AsRef::<str>::as_ref(&self.#v);
+            ))
+        } else {
+            self.measurement_path.as_ref().map(|path| quote!{
+                let measurement = #path(self);
+                let measurement: &str = ::core::convert::AsRef::<str>::as_ref(&measurement);
+            })
+        }
     }
 
     pub fn time_fn(&self) -> proc_macro2::TokenStream {
         match self.time_field {
             Some(ref t) => {
                 let time = &t.struct_field.ident;
-                quote!(fn time(&self) -> std::time::Duration { self.#time })
+                if option_inner(&t.struct_field.ty).is_some() {
+                    quote!(fn time(&self) -> ::core::option::Option<i64> {
+                        self.#time.as_ref().and_then(segment::Timestamp::to_unix_nanos)
+                    })
+                } else {
+                    quote!(fn time(&self) -> ::core::option::Option<i64> { segment::Timestamp::to_unix_nanos(&self.#time) })
+                }
             },
             None =>
-                panic!("no field flagged as metric time"),
+                quote!(fn time(&self) -> ::core::option::Option<i64> { ::core::option::Option::None }),
         }
     }
 
-    pub fn tags_fn(&self) -> proc_macro2::TokenStream {
-        let names: Vec<String> = self.tags.iter().map(|t| t.name.clone()).collect();
-        let vals = self.tags.iter().map(|t| t.struct_field.ident.clone());
-        quote!{
-            fn tags(&self) -> Vec<segment::Tag> {
-                vec!(#(
-                        segment::Tag{
-                            name: #names.to_string(),
-                            value: segment::escape_tagstr(&self.#vals.to_string()),
-                        },
-                )*)
+    /// Generates the code pushing every tag, including those of flattened
+    /// members, to `tags`.
+    fn tag_pushes(&self) -> proc_macro2::TokenStream {
+        let tags = self.tag_entries().into_iter().map(|entry| match entry {
+            TagEntry::Member(t) => {
+                let name = &t.name;
+                let value = t.value_ref();
+                t.unless_skipped(quote!{
+                    if let ::core::option::Option::Some(value) = segment::ToTagValue::to_tag_value(#value) {
+                        tags.push(segment::Tag{
+                            name: #name.to_string(),
+                            value: value.into_owned(),
+                        });
+                    }
+                })
+            },
+            TagEntry::Const(c) => {
+                let (name, value) = (c.name(), c.tag_value());
+                quote!{
+                    tags.push(segment::Tag{
+                        name: #name.to_string(),
+                        value: #value.to_string(),
+                    });
+                }
+            },
+        }).collect::<Vec<_>>();
+        // A flattened tag is left out if its key was already pushed.
+        let flattened = self.flattened.iter().map(|f| {
+            let v = &f.struct_field.ident;
+            let flatten = quote_spanned!(f.struct_field.ty.span()=> segment::Flatten::flatten_tags(&self.#v, &mut flattened););
+            quote!{
+                {
+                    let mut flattened = ::std::vec::Vec::new();
+                    #flatten
+                    for tag in flattened {
+                        if !tags.iter().any(|t: &segment::Tag| t.name == tag.name) {
+                            tags.push(tag);
+                        }
+                    }
+                }
+            }
+        });
+        let maps = map_entries(&self.tag_names(), &self.tag_maps, |value| quote!{
+            if let ::core::option::Option::Some(value) = segment::ToTagValue::to_tag_value(#value) {
+                // Line protocol has no empty tag values, and a key a flattened
+                // member already pushed is left out.
+                if !value.is_empty() && !tags.iter().any(|t: &segment::Tag| t.name == key) {
+                    taken.push(key);
+                    tags.push(segment::Tag{
+                        name: key.to_string(),
+                        value: value.into_owned(),
+                    });
+                }
             }
+        });
+        quote!{
+            #( #tags )*
+            #( #flattened )*
+            #maps
         }
     }
 
-    pub fn fields_fn(&self) -> proc_macro2::TokenStream {
-        let names = self.fields.iter().map(|f| f.name.clone());
-        let vals = self.fields.iter().map(|f| {
-            f.struct_field.ident.clone()
-        });
+    /// Whether the tag set is only known at runtime, and so can only be
+    /// sorted then.
+    fn runtime_tags(&self) -> bool {
+        !self.flattened.is_empty() || !self.tag_maps.is_empty()
+    }
+
+    pub fn tags_fn(&self) -> proc_macro2::TokenStream {
+        let pushes = self.tag_pushes();
+        // Tags of flattened members and maps can only be sorted once known.
+        let sort = if !self.runtime_tags() {
+            quote!()
+        } else {
+            quote!(tags.sort_by(|a, b| a.name.cmp(&b.name));)
+        };
         quote!{
-            fn fields(&self) -> Vec<segment::Field> {
-                vec!(#(segment::Field{
-                    name: #names.to_string(),
-                    value: segment::FieldValue::from(self.#vals.clone()),
-                }, )*)
+            fn tags(&self) -> ::std::vec::Vec<segment::Tag> {
+                let mut tags = ::std::vec::Vec::new();
+                {
+                    let tags = &mut tags;
+                    #pushes
+                }
+                #sort
+                tags
             }
         }
     }
 
-
-    fn tag_vals(&self) -> proc_macro2::TokenStream {
-        let tags = self.tags.iter().map(|t| {
-            let n = &t.name;
-            let v = &t.struct_field.ident;
-            let ty = &t.struct_field.ty;
+    /// Generates the code pushing every field, including those of flattened
+    /// members, to `fields`.
+    fn field_pushes(&self) -> proc_macro2::TokenStream {
+        let consts = self.const_fields.iter().map(|c| {
+            let (name, value) = (c.name(), c.field_value());
             quote!{
-                s.push_str(concat!(",", #n, "="));
-                segment::segment_write!(s, self.#v, #ty, tag);
+                fields.push(segment::Field{
+                    name: #name.to_string(),
+                    value: #value,
+                });
+            }
+        });
+        let fields = self.fields.iter().map(|f| {
+            let name = &f.name;
+            let value = f.value_ref();
+            f.unless_skipped(quote!{
+                if let ::core::option::Option::Some(value) = segment::ToFieldValue::to_field_value(#value) {
+                    fields.push(segment::Field{
+                        name: #name.to_string(),
+                        value,
+                    });
+                }
+            })
+        });
+        let flattened = self.flattened.iter().map(|f| {
+            let v = &f.struct_field.ident;
+            quote_spanned!(f.struct_field.ty.span()=> segment::Flatten::flatten_fields(&self.#v, &mut *fields);)
+        });
+        let maps = map_entries(&self.field_names(), &self.field_maps, |value| quote!{
+            if let ::core::option::Option::Some(value) = segment::ToFieldValue::to_field_value(#value) {
+                taken.push(key);
+                fields.push(segment::Field{
+                    name: key.to_string(),
+                    value,
+                });
             }
         });
+        quote!{
+            #( #consts )*
+            #( #fields )*
+            #( #flattened )*
+            #maps
+        }
+    }
 
+    pub fn fields_fn(&self) -> proc_macro2::TokenStream {
+        let pushes = self.field_pushes();
         quote!{
-            #( #tags )*
+            fn fields(&self) -> ::std::vec::Vec<segment::Field> {
+                let mut fields = ::std::vec::Vec::new();
+                {
+                    let fields = &mut fields;
+                    #pushes
+                }
+                fields
+            }
         }
     }
 
-    fn field_vals(&self) -> proc_macro2::TokenStream {
-        let mut fields = self.fields.iter().map(|f| {
-            let n = &f.name;
-            let v = &f.struct_field.ident;
+    /// Generates the code writing the tag set. A tag whose value is omitted
+    /// is truncated away along with its key.
+    ///
+    /// Tags are sorted by key at compile time, unless tags of flattened
+    /// members or maps are merged in, in which case the whole set is
+    /// gathered and sorted when written. The default tags, in `defaults`,
+    /// are merged in either way, unless the metric has a tag with their key.
+    fn tag_vals(&self) -> proc_macro2::TokenStream {
+        if self.runtime_tags() {
+            let pushes = self.tag_pushes();
+            return quote!{
+                let mut line_tags = ::std::vec::Vec::new();
+                {
+                    let tags = &mut line_tags;
+                    #pushes
+                }
+                for tag in defaults {
+                    if !line_tags.iter().any(|t: &segment::Tag| t.name == tag.name) {
+                        line_tags.push(tag.clone());
+                    }
+                }
+                if let ::core::option::Option::Some(tag) = line_tags.iter().find(|t: &&segment::Tag| t.value.is_empty()) {
+                    return ::core::result::Result::Err(segment::Error::EmptyTagValue(tag.name.clone()));
+                }
+                line_tags.sort_by(|a: &segment::Tag, b: &segment::Tag| a.name.cmp(&b.name));
+                for tag in &line_tags {
+                    s.push(',');
+                    segment::build_escapedtagstr(&tag.name, s);
+                    s.push('=');
+                    segment::build_escapedtagstr(&tag.value, s);
+                }
+            };
+        }
+        let tags = self.tag_entries().into_iter().map(|entry| match entry {
+            TagEntry::Member(t) => {
+                let (name, n) = (&t.name, escape_key(&t.name));
+                let value = t.value_ref();
+                let write = t.unless_skipped(quote!{
+                    let tag_start = s.len();
+                    s.push_str(::core::concat!(",", #n, "="));
+                    let value_start = s.len();
+                    if segment::ToTagValue::write_tag(#value, s)? {
+                        // Line protocol has no empty tag values.
+                        if s.len() == value_start {
+                            return ::core::result::Result::Err(segment::Error::EmptyTagValue(#name.to_string()));
+                        }
+                        written = true;
+                    } else {
+                        s.truncate(tag_start);
+                    }
+                });
+                quote!{
+                    defaults.before(#name, s);
+                    let mut written = false;
+                    #write
+                    defaults.at(#name, written, s);
+                }
+            },
+            // Constant tags are escaped here, once.
+            TagEntry::Const(c) => {
+                let name = c.name();
+                let tag = format!(",{}={}", escape_key(&name), escape_key(&c.tag_value()));
+                quote!{
+                    defaults.before(#name, s);
+                    s.push_str(#tag);
+                    defaults.at(#name, true, s);
+                }
+            },
+        }).collect::<Vec<_>>();
 
-            let ty = &f.struct_field.ty;
+        quote!{
+            let mut defaults = segment::DefaultTags::new(defaults);
+            #( #tags )*
+            defaults.rest(s);
+        }
+    }
 
-            (
-                quote!(concat!(#n,"=")),
-                quote!(segment::segment_write!(s, self.#v, #ty, field);)
-            )
-        });
+    /// Generates the code writing the field set.
+    ///
+    /// Returns the checks to run before anything is written, and the code
+    /// writing the fields. Any field may be omitted, e.g. when it is `None`
+    /// or by the non-finite policy, so separators are placed at runtime and
+    /// an omitted field is truncated away along with its key.
+    fn field_vals(&self) -> (proc_macro2::TokenStream, proc_macro2::TokenStream) {
+        let mut checks = Vec::new();
+        // A line with every optional field `None` has no fields at all.
+        if self.flattened.is_empty() && self.field_maps.is_empty() && self.const_fields.is_empty()
+            && self.fields.iter().all(|f| option_inner(&f.struct_field.ty).is_some()) {
+            let members = self.fields.iter().map(|f| &f.struct_field.ident);
+            checks.push(quote!{
+                if #( self.#members.is_none() )&&* {
+                    return ::core::result::Result::Err(segment::Error::NoFields);
+                }
+            });
+        }
+        let writes = self.field_writes();
 
-        let fields_head = match fields.next() {
-            Some((n, v)) => {
-                Some(quote!{
-                    s.push_str(#n);
-                    #v
-                })
+        (
+            quote!( #( #checks )* ),
+            quote!{
+                let fields_start = s.len();
+                #writes
+                if s.len() == fields_start {
+                    return ::core::result::Result::Ok(false);
+                }
             },
-            None =>
-                None
-        };
+        )
+    }
 
-        let fields_tail = fields.map(|(n, v)| {
+    /// Generates the code writing every field, including those of flattened
+    /// members, each preceded by a comma unless the field set, starting at
+    /// `fields_start`, is still empty.
+    fn field_writes(&self) -> proc_macro2::TokenStream {
+        let consts = self.const_fields.iter().map(|c| {
+            let pair = c.field_pair();
             quote!{
-                s.push_str(concat!(",", #n));
-                #v
+                if s.len() != fields_start {
+                    s.push(',');
+                }
+                s.push_str(#pair);
             }
         });
+        let writes = self.fields.iter().map(|f| {
+            let n = escape_key(&f.name);
+            let value = f.value_ref();
+            let options = if f.unsigned {
+                quote!(&opts.unsigned(segment::Unsigned::Native))
+            } else {
+                quote!(opts)
+            };
+            f.unless_skipped(quote!{
+                let field_start = s.len();
+                if field_start != fields_start {
+                    s.push(',');
+                }
+                s.push_str(::core::concat!(#n, "="));
+                if !segment::ToFieldValue::write_field(#value, s, #options)? {
+                    s.truncate(field_start);
+                }
+            })
+        });
+        let flattened = self.flattened.iter().map(|f| {
+            let v = &f.struct_field.ident;
+            quote_spanned!(f.struct_field.ty.span()=> segment::Flatten::write_fields(&self.#v, s, fields_start, opts)?;)
+        });
+        let maps = map_entries(&self.field_names(), &self.field_maps, |value| quote!{
+            let field_start = s.len();
+            if field_start != fields_start {
+                s.push(',');
+            }
+            segment::build_escapedtagstr(key, s);
+            s.push('=');
+            if segment::ToFieldValue::write_field(#value, s, opts)? {
+                taken.push(key);
+            } else {
+                s.truncate(field_start);
+            }
+        });
+
         quote!{
-            #fields_head
-            #( #fields_tail )*
+            #( #consts )*
+            #( #writes )*
+            #( #flattened )*
+            #maps
         }
     }
 
-    pub fn lineproto_fn(&self) -> proc_macro2::TokenStream {
+    pub fn flatten_fns(&self) -> proc_macro2::TokenStream {
+        let tags = self.tag_pushes();
+        let fields = self.field_pushes();
+        let writes = self.field_writes();
+        quote!{
+            fn flatten_tags(&self, tags: &mut ::std::vec::Vec<segment::Tag>) {
+                #tags
+            }
+
+            fn flatten_fields(&self, fields: &mut ::std::vec::Vec<segment::Field>) {
+                #fields
+            }
+
+            fn write_fields(&self, s: &mut dyn segment::Sink, fields_start: usize, opts: &segment::Options) -> segment::Result<()> {
+                #writes
+                ::core::result::Result::Ok(())
+            }
+        }
+    }
+
+    pub fn build_fn(&self) -> proc_macro2::TokenStream {
         // <measurement>,<tags> <fields> <time>
-        let measurement = &self.measurement;
+        let push_measurement = match self.measurement_ref() {
+            Some(measurement) => quote!{
+                #measurement
+                if measurement.is_empty() {
+                    return ::core::result::Result::Err(segment::Error::EmptyMeasurement);
+                }
+                segment::build_escapedmeasurement(measurement, s);
+            },
+            None => {
+                let measurement = escape_measurement(&self.measurement.value());
+                quote!(s.push_str(#measurement);)
+            },
+        };
         let push_tags = self.tag_vals();
-        let push_fields = self.field_vals();
-        match &self.time_field {
-            None => panic!("no field declared as time of metric"),
+        let (field_checks, push_fields) = self.field_vals();
+        // Without a time member, or when it is `None`, the line is written
+        // without a timestamp, and the server assigns one on arrival.
+        let push_time = match &self.time_field {
+            None => quote!(),
             Some(t) => {
                 let tfield = &t.struct_field.ident;
-                let ns = quote!{
-                    let sec_ns = self.#tfield.as_secs() * 1e9 as u64;
-                    let ns = sec_ns + self.#tfield.subsec_nanos() as u64;
+                let precision = precision_path(t);
+                let time = if option_inner(&t.struct_field.ty).is_some() {
+                    quote!(self.#tfield.as_ref())
+                } else {
+                    quote!(::core::option::Option::Some(&self.#tfield))
                 };
                 quote!{
-                    fn to_lineproto(&self) -> String {
-                        let mut s = String::with_capacity(64);
-                        self.build(&mut s);
-                        s
-                    }
-
-                    fn build(&self, s: &mut String) -> std::io::Result<usize> {
-                        s.push_str(#measurement);
-                        #push_tags
-                        s.push(' ');
-                        #push_fields
+                    if let ::core::option::Option::Some(time) = #time {
                         s.push(' ');
-                        #ns
-                        unsafe {
-                            let mut bytes = s.as_mut_vec();
-                            itoa::write(&mut bytes, ns);
-                        }
-                        Ok(s.len())
+                        let ns = segment::Timestamp::to_unix_nanos(time)
+                            .ok_or(segment::Error::InvalidTimestamp)?;
+                        let ts = opts.precision.unwrap_or(#precision).nanos_to_timestamp(ns);
+                        segment::build_integer(ts, s);
                     }
                 }
             }
+        };
+        quote!{
+            fn build_with(&self, s: &mut dyn segment::Sink, opts: &segment::Options) -> segment::Result<usize> {
+                segment::Metric::build_with_tags(self, s, opts, &[])
+            }
+
+            fn build_with_tags(&self, s: &mut dyn segment::Sink, opts: &segment::Options, defaults: &[segment::Tag]) -> segment::Result<usize> {
+                #field_checks
+
+                let start = s.len();
+                // Returns `false` if every field was omitted.
+                let mut write_line = || -> segment::Result<bool> {
+                    #push_measurement
+                    #push_tags
+                    s.push(' ');
+                    #push_fields
+                    #push_time
+                    ::core::result::Result::Ok(true)
+                };
+                match write_line() {
+                    ::core::result::Result::Ok(true) => ::core::result::Result::Ok(s.len() - start),
+                    ::core::result::Result::Ok(false) => {
+                        s.truncate(start);
+                        ::core::result::Result::Ok(0)
+                    },
+                    ::core::result::Result::Err(e) => {
+                        s.truncate(start);
+                        ::core::result::Result::Err(e)
+                    },
+                }
+            }
         }
     }
 
-    fn process_fields(&mut self, input: &Data) -> Result<(), MetricError> {
-        if let Data::Struct(ref data) = *input {
-            let fields = match data.fields {
-                Fields::Named(ref fields) => fields.named.clone(),
-                Fields::Unnamed(ref fields) => fields.unnamed.clone(),
-                _ => panic!("unknown fields")
-            };
+    pub fn decode_fn(&self) -> proc_macro2::TokenStream {
+        // A measurement member takes the line's measurement, whatever it is,
+        // while one computed by `measurement_fn` is checked once decoded.
+        let check_measurement = if self.measurement_field.is_some() || self.measurement_path.is_some() {
+            quote!()
+        } else {
+            let measurement = &self.measurement;
+            quote!{
+                if point.measurement != #measurement {
+                    return ::core::result::Result::Err(segment::DecodeError::Measurement(point.measurement.clone()));
+                }
+            }
+        };
+        let measurement_member = self.measurement_field.as_ref().map(|m| {
+            let v = &m.struct_field.ident;
+            quote!{
+                #v: segment::FromTagValue::from_tag_value(&point.measurement)
+                    .ok_or_else(|| segment::DecodeError::Measurement(point.measurement.clone()))?,
+            }
+        });
+        let check_decoded = self.measurement_path.as_ref().map(|path| quote!{
+            if ::core::convert::AsRef::<str>::as_ref(&#path(&metric)) != point.measurement {
+                return ::core::result::Result::Err(segment::DecodeError::Measurement(point.measurement.clone()));
+            }
+        });
 
-            for (field_idx, field) in fields.iter().enumerate() {
-                match make_field(field, field_idx) {
-                    None => (),
-                    Some(seg_field) =>
-                        match seg_field.field_type {
-                            SegmentFieldType::Tag =>
-                                self.tags.push(seg_field),
-                            SegmentFieldType::Field =>
-                                self.fields.push(seg_field),
-                            SegmentFieldType::Time =>
-                                self.time_field = Some(seg_field),
-                            SegmentFieldType::Unknown =>
-                                (),
-                        }
+        let tag_vars: Vec<Ident> = self.tags.iter().map(|t| local_ident("tag", t)).collect();
+        let tag_tys = self.tags.iter().map(|t| value_type(&t.struct_field.ty).0);
+        let tag_names: Vec<&String> = self.tags.iter().map(|t| &t.name).collect();
+
+        let field_vars: Vec<Ident> = self.fields.iter().map(|f| local_ident("field", f)).collect();
+        let field_tys = self.fields.iter().map(|f| value_type(&f.struct_field.ty).0);
+        let field_names: Vec<&String> = self.fields.iter().map(|f| &f.name).collect();
+
+        let tag_members = self.tags.iter().map(|t| &t.struct_field.ident);
+        let field_members = self.fields.iter().map(|f| &f.struct_field.ident);
+        let tag_values = self.tags.iter().zip(&tag_vars).map(|(t, var)| {
+            let name = &t.name;
+            t.decoded_member(var, quote!(segment::DecodeError::MissingTag(#name.to_string())))
+        });
+        let field_values = self.fields.iter().zip(&field_vars).map(|(f, var)| {
+            let name = &f.name;
+            f.decoded_member(var, quote!(segment::DecodeError::MissingField(#name.to_string())))
+        });
+        let other_members = self.others.iter().map(|o| &o.struct_field.ident);
+        let tag_decoded = self.tags.iter()
+            .map(|t| t.decoded(quote!(segment::FromTagValue::from_tag_value(&tag.value))));
+        let field_decoded = self.fields.iter()
+            .map(|f| f.decoded(quote!(segment::FromFieldValue::from_field_value(&field.value))));
+        let (tag_vars, tag_names) = (&tag_vars, &tag_names);
+        let (field_vars, field_names) = (&field_vars, &field_names);
+        // Constant tags and fields are checked, but not required.
+        let const_tags = self.const_tags.iter().map(|c| {
+            let (name, value) = (c.name(), c.tag_value());
+            quote!{
+                #name => if tag.value != #value {
+                    return ::core::result::Result::Err(segment::DecodeError::InvalidTag(tag.name.clone()));
+                },
+            }
+        });
+        let const_fields = self.const_fields.iter().map(|c| {
+            let (name, value) = (c.name(), c.field_value());
+            quote!{
+                #name => if field.value != #value {
+                    return ::core::result::Result::Err(segment::DecodeError::InvalidField(field.name.clone()));
+                },
+            }
+        });
+
+        let time = match self.time_field {
+            Some(ref t) => {
+                let member = &t.struct_field.ident;
+                let precision = precision_path(t);
+                let missing = if option_inner(&t.struct_field.ty).is_some() {
+                    quote!(::core::option::Option::None)
+                } else {
+                    quote!(return ::core::result::Result::Err(segment::DecodeError::MissingTime))
+                };
+                let decoded = if option_inner(&t.struct_field.ty).is_some() {
+                    quote!(::core::option::Option::Some(segment::decode_timestamp(ts, #precision)?))
+                } else {
+                    quote!(segment::decode_timestamp(ts, #precision)?)
+                };
+                quote!{
+                    #member: match point.timestamp {
+                        ::core::option::Option::Some(ts) => #decoded,
+                        ::core::option::Option::None => #missing,
+                    },
+                }
+            },
+            None => quote!(),
+        };
+
+        quote!{
+            fn from_point(point: &segment::Point) -> ::core::result::Result<Self, segment::DecodeError> {
+                #check_measurement
+
+                #( let mut #tag_vars: ::core::option::Option<#tag_tys> = ::core::option::Option::None; )*
+                for tag in &point.tags {
+                    match tag.name.as_str() {
+                        #( #tag_names => #tag_vars = ::core::option::Option::Some(
+                            #tag_decoded
+                                .ok_or_else(|| segment::DecodeError::InvalidTag(tag.name.clone()))?
+                        ), )*
+                        #( #const_tags )*
+                        _ => return ::core::result::Result::Err(segment::DecodeError::UnexpectedTag(tag.name.clone())),
+                    }
+                }
+
+                #( let mut #field_vars: ::core::option::Option<#field_tys> = ::core::option::Option::None; )*
+                for field in &point.fields {
+                    match field.name.as_str() {
+                        #( #field_names => #field_vars = ::core::option::Option::Some(
+                            #field_decoded
+                                .ok_or_else(|| segment::DecodeError::InvalidField(field.name.clone()))?
+                        ), )*
+                        #( #const_fields )*
+                        _ => return ::core::result::Result::Err(segment::DecodeError::UnexpectedField(field.name.clone())),
+                    }
                 }
+
+                let metric = Self {
+                    #time
+                    #measurement_member
+                    #( #tag_members: #tag_values, )*
+                    #( #field_members: #field_values, )*
+                    #( #other_members: ::core::default::Default::default(), )*
+                };
+                #check_decoded
+                ::core::result::Result::Ok(metric)
+            }
+        }
+    }
+
+    fn process_fields(&mut self, input: &DeriveInput) -> syn::Result<()> {
+        let data = match input.data {
+            Data::Struct(ref data) => data,
+            Data::Enum(ref data) =>
+                return Err(syn::Error::new_spanned(data.enum_token, "metrics can only be derived for structs, not enums")),
+            Data::Union(ref data) =>
+                return Err(syn::Error::new_spanned(data.union_token, "metrics can only be derived for structs, not unions")),
+        };
+        let fields = match data.fields {
+            Fields::Named(ref fields) => &fields.named,
+            Fields::Unnamed(ref fields) =>
+                return Err(syn::Error::new_spanned(fields, "metrics can only be derived for structs with named members")),
+            Fields::Unit =>
+                return Err(syn::Error::new_spanned(
+                    &input.ident,
+                    "no fields defined for metric, mark at least one member with `#[segment(field)]`")),
+        };
+
+        for field in fields.iter() {
+            let seg_field = make_field(field)?;
+            match seg_field.field_type {
+                SegmentFieldType::Tag if self.tags.iter().any(|t| t.name == seg_field.name) =>
+                    return Err(syn::Error::new_spanned(
+                        &seg_field.struct_field,
+                        format!("duplicate tag \"{}\"", seg_field.name))),
+                SegmentFieldType::Tag =>
+                    self.tags.push(seg_field),
+                SegmentFieldType::Field if self.fields.iter().any(|f| f.name == seg_field.name) =>
+                    return Err(syn::Error::new_spanned(
+                        &seg_field.struct_field,
+                        format!("duplicate field \"{}\"", seg_field.name))),
+                SegmentFieldType::Field =>
+                    self.fields.push(seg_field),
+                SegmentFieldType::Time if self.time_field.is_some() =>
+                    return Err(syn::Error::new_spanned(
+                        &seg_field.struct_field,
+                        "duplicate time member, only one member can be marked `#[segment(time)]`")),
+                SegmentFieldType::Time =>
+                    self.time_field = Some(seg_field),
+                SegmentFieldType::Measurement if self.measurement_field.is_some() =>
+                    return Err(syn::Error::new_spanned(
+                        &seg_field.struct_field,
+                        "duplicate measurement member, only one member can be marked `#[segment(measurement)]`")),
+                SegmentFieldType::Measurement =>
+                    self.measurement_field = Some(seg_field),
+                SegmentFieldType::Flatten =>
+                    self.flattened.push(seg_field),
+                SegmentFieldType::TagMap =>
+                    self.tag_maps.push(seg_field),
+                SegmentFieldType::FieldMap =>
+                    self.field_maps.push(seg_field),
+                SegmentFieldType::Unknown =>
+                    self.others.push(seg_field),
             }
-        } else {
-            panic!("only structs supported");
         }
 
         // Sort our tags lexographically, per Influx Data recommendation.
@@ -253,75 +1011,516 @@ impl SegmentMetric {
     }
 }
 
+/// Derives `Metric` for a struct whose members are marked with
+/// `#[segment(...)]` attributes.
+///
+/// The entries of maps marked `#[segment(tags)]` or `#[segment(fields)]` are
+/// merged into the line's tags or fields. An entry is left out when its key
+/// is empty, is that of a tag or field declared on the same struct, or was
+/// already taken by an earlier entry; tag entries are also left out when
+/// their value is empty or their key is that of a flattened tag.
+///
+/// Likewise, a tag of a flattened member is left out when its key is that
+/// of a tag of the struct or of an earlier flattened member.
 #[proc_macro_derive(Metric, attributes(segment))]
 pub fn metric_macro(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
 
     let metric = match SegmentMetric::build(input) {
         Ok(m) => m,
-        Err(e) => panic!("Error"),
+        Err(e) => return TokenStream::from(e.to_compile_error()),
     };
 
     let name = &metric.name;
+    let (impl_generics, ty_generics, where_clause) = metric.generics.split_for_impl();
     let measurement = metric.measurement_fn();
     let time = metric.time_fn();
     let tags = metric.tags_fn();
     let fields = metric.fields_fn();
-    let to_lineproto = metric.lineproto_fn();
+    let build = metric.build_fn();
 
     TokenStream::from(quote!{
-        impl Metric for #name {
+        impl #impl_generics segment::Metric for #name #ty_generics #where_clause {
             #time
             #measurement
             #tags
             #fields
-            #to_lineproto
+            #build
+        }
+    })
+}
+
+/// Derives `FromLineProto` for a struct whose members are marked with
+/// `#[segment(...)]` attributes, the same as for `Metric`.
+///
+/// Tags and fields of the line are matched to members by their unescaped
+/// keys, including those given with `rename`; keys no member declares are
+/// rejected. A missing optional member is `None`, and a missing member marked
+/// `default` takes its default value, while any other missing member is an
+/// error. Unmarked members are set with `Default::default()`.
+///
+/// Flattened members and maps cannot be decoded, since the keys they hold
+/// are only known once written.
+#[proc_macro_derive(FromLineProto, attributes(segment))]
+pub fn from_lineproto_macro(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    let metric = match SegmentMetric::build(input) {
+        Ok(m) => m,
+        Err(e) => return TokenStream::from(e.to_compile_error()),
+    };
+
+    if let Some(f) = metric.flattened.first() {
+        return TokenStream::from(syn::Error::new_spanned(
+            &f.struct_field,
+            "`FromLineProto` cannot be derived for metrics with flattened members").to_compile_error());
+    }
+    if let Some(f) = metric.tag_maps.iter().chain(&metric.field_maps).next() {
+        return TokenStream::from(syn::Error::new_spanned(
+            &f.struct_field,
+            "`FromLineProto` cannot be derived for metrics with tag or field maps").to_compile_error());
+    }
+
+    let name = &metric.name;
+    let (impl_generics, ty_generics, where_clause) = metric.generics.split_for_impl();
+    let from_point = metric.decode_fn();
+
+    TokenStream::from(quote!{
+        impl #impl_generics segment::FromLineProto for #name #ty_generics #where_clause {
+            #from_point
+        }
+    })
+}
+
+/// Generates the code adding the entries of `maps`, after the tags or fields
+/// `declared` on the struct, through `add`.
+///
+/// Keys are borrowed as `&str` into `key`. An entry is left out when its key
+/// is empty, is that of a declared tag or field, or was added from an earlier
+/// entry; `add` must push the keys it adds to `taken`.
+fn map_entries<F>(declared: &[String], maps: &[SegmentField], add: F) -> proc_macro2::TokenStream
+    where F: Fn(proc_macro2::TokenStream) -> proc_macro2::TokenStream
+{
+    if maps.is_empty() {
+        return quote!();
+    }
+    let names = declared.iter();
+    let entries = maps.iter().map(|m| {
+        let v = &m.struct_field.ident;
+        let entries = quote_spanned!(m.struct_field.ty.span()=> &self.#v);
+        let add = add(quote_spanned!(m.struct_field.ty.span()=> value));
+        quote!{
+            for (key, value) in #entries {
+                let key: &str = key.as_ref();
+                if key.is_empty() || taken.contains(&key) {
+                    continue;
+                }
+                #add
+            }
+        }
+    });
+    quote!{
+        {
+            let mut taken: ::std::vec::Vec<&str> = ::std::vec![#( #names ),*];
+            #( #entries )*
+        }
+    }
+}
+
+/// Converts the value of a constant tag or field: a string, integer, float
+/// or bool literal, optionally negated.
+fn const_value(expr: &syn::Expr) -> syn::Result<ConstValue> {
+    let (lit, negated) = match expr {
+        syn::Expr::Lit(lit) => (&lit.lit, false),
+        syn::Expr::Unary(syn::ExprUnary{ op: syn::UnOp::Neg(_), expr, .. }) => match **expr {
+            syn::Expr::Lit(ref lit) => (&lit.lit, true),
+            _ => return Err(syn::Error::new_spanned(expr, "expected a string, integer, float or bool literal")),
+        },
+        _ => return Err(syn::Error::new_spanned(expr, "expected a string, integer, float or bool literal")),
+    };
+    match lit {
+        syn::Lit::Str(s) if !negated => Ok(ConstValue::Str(s.value())),
+        syn::Lit::Bool(b) if !negated => Ok(ConstValue::Bool(b.value)),
+        syn::Lit::Int(i) => {
+            let digits = if negated { format!("-{}", i.base10_digits()) } else { i.base10_digits().to_string() };
+            digits.parse().map(ConstValue::Int)
+                .map_err(|_| syn::Error::new_spanned(i, "integer out of range for an `i64` field"))
+        },
+        syn::Lit::Float(f) => {
+            let value: f64 = f.base10_parse()?;
+            if !value.is_finite() {
+                return Err(syn::Error::new_spanned(f, "float out of range for an `f64` field"));
+            }
+            Ok(ConstValue::Float(if negated { -value } else { value }))
+        },
+        _ => Err(syn::Error::new_spanned(expr, "expected a string, integer, float or bool literal")),
+    }
+}
+
+/// Derives `Flatten` for a struct of tags and fields, which members of a
+/// metric marked `#[segment(flatten)]` merge into the metric's line.
+#[proc_macro_derive(Flatten, attributes(segment))]
+pub fn flatten_macro(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    let group = match SegmentMetric::build_group(input) {
+        Ok(g) => g,
+        Err(e) => return TokenStream::from(e.to_compile_error()),
+    };
+
+    let name = &group.name;
+    let (impl_generics, ty_generics, where_clause) = group.generics.split_for_impl();
+    let flatten = group.flatten_fns();
+
+    TokenStream::from(quote!{
+        impl #impl_generics segment::Flatten for #name #ty_generics #where_clause {
+            #flatten
         }
     })
 }
 
-// TODO: Sanity check types: tags need to be strings.. time needs to be duration.
-fn make_field(field: &Field, field_idx: usize) -> Option<SegmentField> {
+/// A unit variant of an enum deriving `TagValue`.
+struct TagVariant {
+    ident: Ident,
+    // The value written for the variant
+    name: LitStr,
+}
+
+/// Gathers the variants of an enum deriving `TagValue`, with their values.
+fn tag_variants(input: &DeriveInput) -> syn::Result<Vec<TagVariant>> {
+    for_each_segment_meta(&input.attrs, |meta| {
+        Err(meta.error("unknown segment attribute, `TagValue` takes no attributes on the enum"))
+    })?;
+    let data = match input.data {
+        Data::Enum(ref data) => data,
+        Data::Struct(ref data) =>
+            return Err(syn::Error::new_spanned(data.struct_token, "`TagValue` can only be derived for enums")),
+        Data::Union(ref data) =>
+            return Err(syn::Error::new_spanned(data.union_token, "`TagValue` can only be derived for enums")),
+    };
+    if data.variants.is_empty() {
+        return Err(syn::Error::new_spanned(&input.ident, "`TagValue` cannot be derived for an enum without variants"));
+    }
+
+    let mut variants: Vec<TagVariant> = Vec::with_capacity(data.variants.len());
+    for variant in &data.variants {
+        if !matches!(variant.fields, Fields::Unit) {
+            return Err(syn::Error::new_spanned(&variant.fields, "`TagValue` can only be derived for enums whose variants have no fields"));
+        }
+        let mut name = LitStr::new(&variant.ident.to_string(), variant.ident.span());
+        for_each_segment_meta(&variant.attrs, |meta| {
+            if meta.path.is_ident("rename") {
+                name = string_value(&meta, "rename")?;
+                if name.value().is_empty() {
+                    return Err(syn::Error::new_spanned(&name, "`rename` cannot be empty"));
+                }
+                Ok(())
+            } else {
+                Err(meta.error("unknown segment attribute, expected `rename = \"...\"`"))
+            }
+        })?;
+        if variants.iter().any(|v| v.name.value() == name.value()) {
+            return Err(syn::Error::new_spanned(
+                &name,
+                format!("duplicate value \"{}\", every variant must be written differently", name.value())));
+        }
+        variants.push(TagVariant{ ident: variant.ident.clone(), name });
+    }
+    Ok(variants)
+}
+
+/// Derives `ToTagValue`, `ToFieldValue`, `FromTagValue` and `FromFieldValue`
+/// for a C-like enum, writing each variant as its name, or as the value given
+/// by `#[segment(rename = "...")]`. As a field, a variant is a string.
+#[proc_macro_derive(TagValue, attributes(segment))]
+pub fn tag_value_macro(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    let variants = match tag_variants(&input) {
+        Ok(v) => v,
+        Err(e) => return TokenStream::from(e.to_compile_error()),
+    };
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let idents: Vec<&Ident> = variants.iter().map(|v| &v.ident).collect();
+    let idents = &idents;
+    let values: Vec<&LitStr> = variants.iter().map(|v| &v.name).collect();
+    let values = &values;
+    // Values are escaped once, here, rather than on every write.
+    let tags = variants.iter().map(|v| escape_key(&v.name.value()));
+    let fields = variants.iter().map(|v| format!("\"{}\"", escape_static(&v.name.value(), &['"', '\\'])));
+
+    TokenStream::from(quote!{
+        impl #impl_generics segment::ToTagValue for #name #ty_generics #where_clause {
+            fn to_tag_value(&self) -> ::core::option::Option<::std::borrow::Cow<'_, str>> {
+                ::core::option::Option::Some(::std::borrow::Cow::Borrowed(match *self {
+                    #( #name::#idents => #values, )*
+                }))
+            }
+
+            fn write_tag<__S: segment::Sink + ?Sized>(&self, buff: &mut __S) -> segment::Result<bool> {
+                buff.push_str(match *self {
+                    #( #name::#idents => #tags, )*
+                });
+                ::core::result::Result::Ok(true)
+            }
+        }
+
+        impl #impl_generics segment::ToFieldValue for #name #ty_generics #where_clause {
+            fn to_field_value(&self) -> ::core::option::Option<segment::FieldValue> {
+                segment::ToTagValue::to_tag_value(self)
+                    .map(|value| segment::FieldValue::Str(value.into_owned()))
+            }
+
+            fn write_field<__S: segment::Sink + ?Sized>(&self, buff: &mut __S, _: &segment::Options) -> segment::Result<bool> {
+                buff.push_str(match *self {
+                    #( #name::#idents => #fields, )*
+                });
+                ::core::result::Result::Ok(true)
+            }
+        }
+
+        impl #impl_generics segment::FromTagValue for #name #ty_generics #where_clause {
+            fn from_tag_value(value: &str) -> ::core::option::Option<Self> {
+                match value {
+                    #( #values => ::core::option::Option::Some(#name::#idents), )*
+                    _ => ::core::option::Option::None,
+                }
+            }
+        }
+
+        impl #impl_generics segment::FromFieldValue for #name #ty_generics #where_clause {
+            fn from_field_value(value: &segment::FieldValue) -> ::core::option::Option<Self> {
+                match value {
+                    segment::FieldValue::Str(s) => segment::FromTagValue::from_tag_value(s),
+                    _ => ::core::option::Option::None,
+                }
+            }
+        }
+    })
+}
+
+/// Returns the name of `ty` if it is a bare, single identifier, type.
+fn simple_type(ty: &syn::Type) -> Option<String> {
+    match ty {
+        syn::Type::Path(p) if p.qself.is_none() && p.path.segments.len() == 1 =>
+            Some(p.path.segments[0].ident.to_string()),
+        _ => None,
+    }
+}
+
+/// Returns `T` if `ty` is `Option<T>`.
+fn option_inner(ty: &syn::Type) -> Option<&syn::Type> {
+    let seg = match ty {
+        syn::Type::Path(p) if p.qself.is_none() => p.path.segments.last()?,
+        _ => return None,
+    };
+    if seg.ident != "Option" {
+        return None;
+    }
+    match &seg.arguments {
+        syn::PathArguments::AngleBracketed(args) if args.args.len() == 1 => match args.args.first()? {
+            syn::GenericArgument::Type(inner) => Some(inner),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Returns the type of the value held by a member of type `ty`, and whether
+/// the member is optional.
+fn value_type(ty: &syn::Type) -> (&syn::Type, bool) {
+    match option_inner(ty) {
+        Some(inner) => (inner, true),
+        None => (ty, false),
+    }
+}
+
+/// Maps the value of a `precision = "..."` attribute to a variant of
+/// `segment::Precision`.
+fn precision_variant(lit: &LitStr) -> syn::Result<Ident> {
+    let variant = match lit.value().as_str() {
+        "ns" | "n" => "Nanoseconds",
+        "us" | "u" | "µs" => "Microseconds",
+        "ms" => "Milliseconds",
+        "s" => "Seconds",
+        _ => return Err(syn::Error::new_spanned(lit, "unknown precision, expected one of \"ns\", \"us\", \"ms\" or \"s\"")),
+    };
+    Ok(Ident::new(variant, lit.span()))
+}
+
+/// The `segment::Precision` of a time member, nanoseconds unless declared.
+fn precision_path(field: &SegmentField) -> proc_macro2::TokenStream {
+    match field.precision {
+        Some(ref variant) => quote!(segment::Precision::#variant),
+        None => quote!(segment::Precision::Nanoseconds),
+    }
+}
+
+/// Whether `ty` is a primitive other than an unsigned integer. Any other
+/// type, such as an alias or a newtype, may still write an unsigned integer.
+fn is_signed_primitive(ty: &syn::Type) -> bool {
+    match simple_type(ty) {
+        Some(ref t) => [
+            "bool", "char", "str", "String", "f32", "f64",
+            "i8", "i16", "i32", "i64", "i128", "isize",
+        ].contains(&t.as_str()),
+        None => false,
+    }
+}
+
+/// Escapes a measurement name known at compile time, matching
+/// `segment::build_escapedmeasurement`.
+fn escape_measurement(s: &str) -> String {
+    escape_static(s, &[',', ' ', '\\'])
+}
+
+/// Escapes a tag or field key known at compile time, matching
+/// `segment::build_escapedtagstr`.
+fn escape_key(s: &str) -> String {
+    escape_static(s, &[',', '=', ' ', '\\'])
+}
+
+fn escape_static(s: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\n' => escaped.push_str("\\n"),
+            c if special.contains(&c) => {
+                escaped.push('\\');
+                escaped.push(c);
+            },
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Name of the local variable holding a decoded member in `from_point`.
+fn local_ident(prefix: &str, field: &SegmentField) -> Ident {
+    let name = match field.struct_field.ident {
+        Some(ref id) => format!("__{}_{}", prefix, id),
+        None => format!("__{}_{}", prefix, field.name),
+    };
+    Ident::new(&name, proc_macro2::Span::call_site())
+}
+
+fn make_field(field: &Field) -> syn::Result<SegmentField> {
     let mut seg_field: SegmentField = SegmentField{
         struct_field: field.clone(),
         field_type: SegmentFieldType::Unknown,
+        unsigned: false,
+        precision: None,
+        display: false,
+        skip_if: None,
+        default: None,
         name: match &field.ident {
-            Some(id) => format!("{}", id).to_string(),
-            None => format!("{}", field_idx).to_string(),
+            Some(id) => id.to_string(),
+            None => unreachable!("tuple structs are rejected before their fields"),
         }
     };
 
-    for meta_item in field.attrs.iter().filter_map(get_segment_meta).flat_map(|x| x) {
-        match meta_item {
-            Meta(Word(ref w)) if w == "tag" =>
-                seg_field.field_type = SegmentFieldType::Tag,
-            Meta(Word(ref w)) if w == "field" =>
-                seg_field.field_type = SegmentFieldType::Field,
-            Meta(Word(ref w)) if w == "time" =>
-                seg_field.field_type = SegmentFieldType::Time,
-            Meta(NameValue(ref n)) if n.ident == "rename" => match &n.lit {
-                Lit::Str(s) =>
-                    seg_field.name = s.value(),
-                _ =>
-                    println!("Other Lit"),
-            },
-            _ =>
-                println!("Unexpected attribute value"),
+    for_each_segment_meta(&field.attrs, |meta| {
+        if meta.path.is_ident("tag") {
+            set_field_type(&mut seg_field, &meta, SegmentFieldType::Tag)
+        } else if meta.path.is_ident("field") {
+            set_field_type(&mut seg_field, &meta, SegmentFieldType::Field)
+        } else if meta.path.is_ident("time") {
+            set_field_type(&mut seg_field, &meta, SegmentFieldType::Time)
+        } else if meta.path.is_ident("measurement") {
+            set_field_type(&mut seg_field, &meta, SegmentFieldType::Measurement)
+        } else if meta.path.is_ident("flatten") {
+            set_field_type(&mut seg_field, &meta, SegmentFieldType::Flatten)
+        } else if meta.path.is_ident("tags") {
+            set_field_type(&mut seg_field, &meta, SegmentFieldType::TagMap)
+        } else if meta.path.is_ident("fields") {
+            set_field_type(&mut seg_field, &meta, SegmentFieldType::FieldMap)
+        } else if meta.path.is_ident("unsigned") {
+            seg_field.unsigned = true;
+            Ok(())
+        } else if meta.path.is_ident("display") {
+            seg_field.display = true;
+            Ok(())
+        } else if meta.path.is_ident("skip_if") {
+            seg_field.skip_if = Some(string_value(&meta, "skip_if")?.parse()?);
+            Ok(())
+        } else if meta.path.is_ident("default") {
+            seg_field.default = Some(if meta.input.peek(syn::Token![=]) {
+                MemberDefault::Path(string_value(&meta, "default")?.parse()?)
+            } else {
+                MemberDefault::Trait
+            });
+            Ok(())
+        } else if meta.path.is_ident("rename") {
+            let name = string_value(&meta, "rename")?;
+            if name.value().is_empty() {
+                return Err(syn::Error::new_spanned(name, "`rename` cannot be empty"));
+            }
+            seg_field.name = name.value();
+            Ok(())
+        } else if meta.path.is_ident("precision") {
+            seg_field.precision = Some(precision_variant(&string_value(&meta, "precision")?)?);
+            Ok(())
+        } else {
+            Err(meta.error("unknown segment attribute, expected one of `tag`, `field`, `time`, `measurement`, `flatten`, `tags`, `fields`, `unsigned`, `display`, `skip_if`, `default`, `rename` or `precision`"))
         }
+    })?;
+
+    let ty = &seg_field.struct_field.ty;
+    let renamed = field.ident.as_ref().is_some_and(|id| *id != seg_field.name);
+    if renamed && matches!(seg_field.field_type, SegmentFieldType::Flatten) {
+        return Err(syn::Error::new_spanned(&seg_field.struct_field, "`rename` cannot be used on flattened members"));
+    }
+    if renamed && matches!(seg_field.field_type, SegmentFieldType::TagMap | SegmentFieldType::FieldMap) {
+        return Err(syn::Error::new_spanned(&seg_field.struct_field, "`rename` cannot be used on maps, their keys are used"));
+    }
+    if renamed && matches!(seg_field.field_type, SegmentFieldType::Measurement) {
+        return Err(syn::Error::new_spanned(&seg_field.struct_field, "`rename` cannot be used on the measurement member"));
     }
-    match seg_field.field_type {
-        SegmentFieldType::Unknown => None,
-        _ => Some(seg_field),
+    if seg_field.precision.is_some() && !matches!(seg_field.field_type, SegmentFieldType::Time) {
+        return Err(syn::Error::new_spanned(&seg_field.struct_field, "`precision` can only be used on the time member"));
     }
+    let is_value = matches!(seg_field.field_type, SegmentFieldType::Tag | SegmentFieldType::Field);
+    if seg_field.display && !is_value {
+        return Err(syn::Error::new_spanned(&seg_field.struct_field, "`display` can only be used on tags and fields"));
+    }
+    if seg_field.skip_if.is_some() && !is_value {
+        return Err(syn::Error::new_spanned(&seg_field.struct_field, "`skip_if` can only be used on tags and fields"));
+    }
+    if seg_field.default.is_some() && !is_value {
+        return Err(syn::Error::new_spanned(&seg_field.struct_field, "`default` can only be used on tags and fields"));
+    }
+    if seg_field.unsigned && (seg_field.display || !matches!(seg_field.field_type, SegmentFieldType::Field) || is_signed_primitive(value_type(ty).0)) {
+        return Err(syn::Error::new_spanned(ty, "`unsigned` can only be used on unsigned integer fields"));
+    }
+    Ok(seg_field)
 }
 
-fn get_segment_meta(attr: &syn::Attribute) -> Option<Vec<syn::NestedMeta>> {
-    if attr.path.segments.len() == 1 && attr.path.segments[0].ident == "segment" {
-        match attr.interpret_meta() {
-            Some(List(ref meta)) => Some(meta.nested.iter().cloned().collect()),
-            _ => None
-        }
-    } else {
-        None
+/// Marks `field` as a tag, field or time member, rejecting members that are
+/// given more than one of these.
+fn set_field_type(field: &mut SegmentField, meta: &ParseNestedMeta, field_type: SegmentFieldType) -> syn::Result<()> {
+    if !matches!(field.field_type, SegmentFieldType::Unknown) {
+        return Err(meta.error("a member can only be one of `tag`, `field`, `time`, `measurement`, `flatten`, `tags` or `fields`"));
+    }
+    field.field_type = field_type;
+    Ok(())
+}
+
+/// Parses the string literal given to the attribute `name`, as in `name = "..."`.
+fn string_value(meta: &ParseNestedMeta, name: &str) -> syn::Result<LitStr> {
+    match meta.value()?.parse()? {
+        syn::Lit::Str(s) => Ok(s),
+        other => Err(syn::Error::new_spanned(other, format!("`{}` must be a string literal", name))),
+    }
+}
+
+/// Calls `parse` with every item of every `#[segment(...)]` attribute in `attrs`.
+fn for_each_segment_meta<F>(attrs: &[syn::Attribute], mut parse: F) -> syn::Result<()>
+    where F: FnMut(ParseNestedMeta) -> syn::Result<()>
+{
+    for attr in attrs.iter().filter(|a| a.path().is_ident("segment")) {
+        attr.parse_nested_meta(&mut parse)?;
     }
+    Ok(())
 }
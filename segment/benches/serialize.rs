@@ -211,7 +211,7 @@ fn criterion_benchmark(c: &mut Criterion) {
         b.iter_batched_ref(
             || String::with_capacity(3048),
             |buffer: &mut String| {
-                procstats.build(buffer);
+                let _ = procstats.build(buffer);
                 buffer.clear();
             },
             BatchSize::SmallInput
@@ -229,7 +229,7 @@ fn criterion_benchmark(c: &mut Criterion) {
         b.iter_batched_ref(
             || String::with_capacity(128),
             |buffer: &mut String| {
-                strings.build(buffer);
+                let _ = strings.build(buffer);
                 buffer.clear();
             },
             BatchSize::SmallInput
@@ -57,11 +57,11 @@ fn main() {
 
     println!("Fields:");
     for f in m.fields() {
-        println!("   - \"{}\" = {}", f.name, f.value.to_string());
+        println!("   - \"{}\" = {}", f.name, f.value);
     }
 
     let mut s = String::with_capacity(64);
-    m.build(&mut s);
+    m.build(&mut s).expect("unable to build line");
 
     println!("Line Proto: '{}'", s);
 }
@@ -0,0 +1,272 @@
+//! Decoding of parsed points back into Rust types.
+//!
+//! `#[derive(FromLineProto)]` generates a [`FromLineProto`] implementation
+//! that mirrors the `Metric` derive: tags and fields are matched by their
+//! (renamed) keys, and converted into each member's type through
+//! [`FromTagValue`] and [`FromFieldValue`].
+
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+use std::num::{
+    NonZeroI16, NonZeroI32, NonZeroI64, NonZeroI8, NonZeroIsize,
+    NonZeroU16, NonZeroU32, NonZeroU64, NonZeroU8, NonZeroUsize, Wrapping,
+};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::parse::parse_bool;
+use crate::{parse_line, Displayed, FieldValue, ParseError, Point, Precision, Timestamp};
+
+/// An error encountered while decoding a line into a type.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    /// The line could not be parsed.
+    Parse(ParseError),
+    /// The line belongs to a different measurement.
+    Measurement(String),
+    /// A tag required by the type is not present.
+    MissingTag(String),
+    /// A field required by the type is not present.
+    MissingField(String),
+    /// The type has a time member, but the line has no timestamp.
+    MissingTime,
+    /// The line has a tag the type does not declare.
+    UnexpectedTag(String),
+    /// The line has a field the type does not declare.
+    UnexpectedField(String),
+    /// A tag value cannot be converted into the member's type.
+    InvalidTag(String),
+    /// A field value has the wrong type, or is out of range, for the member.
+    InvalidField(String),
+    /// The timestamp cannot be represented by the member's type.
+    InvalidTime(i64),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Parse(e) => write!(f, "{}", e),
+            DecodeError::Measurement(m) => write!(f, "unexpected measurement \"{}\"", m),
+            DecodeError::MissingTag(k) => write!(f, "missing tag \"{}\"", k),
+            DecodeError::MissingField(k) => write!(f, "missing field \"{}\"", k),
+            DecodeError::MissingTime => write!(f, "missing timestamp"),
+            DecodeError::UnexpectedTag(k) => write!(f, "unexpected tag \"{}\"", k),
+            DecodeError::UnexpectedField(k) => write!(f, "unexpected field \"{}\"", k),
+            DecodeError::InvalidTag(k) => write!(f, "invalid value for tag \"{}\"", k),
+            DecodeError::InvalidField(k) => write!(f, "invalid value for field \"{}\"", k),
+            DecodeError::InvalidTime(t) => write!(f, "invalid timestamp {}", t),
+        }
+    }
+}
+
+impl error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            DecodeError::Parse(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ParseError> for DecodeError {
+    fn from(e: ParseError) -> Self {
+        DecodeError::Parse(e)
+    }
+}
+
+/// A type that can be constructed from a point of line protocol.
+///
+/// This is usually derived with `#[derive(FromLineProto)]`, using the same
+/// `#[segment(...)]` attributes as `#[derive(Metric)]`.
+pub trait FromLineProto: Sized {
+    /// Builds `Self` from an already parsed point.
+    fn from_point(point: &Point) -> Result<Self, DecodeError>;
+
+    /// Parses a single line, and builds `Self` from it.
+    fn from_lineproto(line: &str) -> Result<Self, DecodeError> {
+        let point = Point::from(parse_line(line)?);
+        Self::from_point(&point)
+    }
+}
+
+/// Conversion from a tag value into a struct member.
+pub trait FromTagValue: Sized {
+    /// Returns `None` if `value` cannot be represented by `Self`.
+    fn from_tag_value(value: &str) -> Option<Self>;
+}
+
+impl FromTagValue for String {
+    fn from_tag_value(value: &str) -> Option<Self> {
+        Some(value.to_string())
+    }
+}
+
+impl FromTagValue for bool {
+    fn from_tag_value(value: &str) -> Option<Self> {
+        parse_bool(value)
+    }
+}
+
+macro_rules! from_tag_value_parse {
+    ( $( $t:ty ),* ) => {
+        $(
+            impl FromTagValue for $t {
+                fn from_tag_value(value: &str) -> Option<Self> {
+                    value.parse().ok()
+                }
+            }
+        )*
+    };
+}
+
+from_tag_value_parse!(
+    i8, i16, i32, i64, isize, u8, u16, u32, u64, usize, f32, f64,
+    NonZeroI8, NonZeroI16, NonZeroI32, NonZeroI64, NonZeroIsize,
+    NonZeroU8, NonZeroU16, NonZeroU32, NonZeroU64, NonZeroUsize
+);
+
+/// Conversion from a field value into a struct member.
+pub trait FromFieldValue: Sized {
+    /// Returns `None` if `value` has the wrong type, or is out of range, for `Self`.
+    fn from_field_value(value: &FieldValue) -> Option<Self>;
+}
+
+impl FromFieldValue for String {
+    fn from_field_value(value: &FieldValue) -> Option<Self> {
+        match value {
+            FieldValue::Str(s) => Some(s.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl FromFieldValue for bool {
+    fn from_field_value(value: &FieldValue) -> Option<Self> {
+        match *value {
+            FieldValue::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! from_field_value_int {
+    ( $( $t:ty ),* ) => {
+        $(
+            impl FromFieldValue for $t {
+                fn from_field_value(value: &FieldValue) -> Option<Self> {
+                    match *value {
+                        FieldValue::UInt32(u) => <$t>::try_from(u).ok(),
+                        FieldValue::UInt64(u) => <$t>::try_from(u).ok(),
+                        FieldValue::Int32(i) => <$t>::try_from(i).ok(),
+                        FieldValue::Int64(i) => <$t>::try_from(i).ok(),
+                        _ => None,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+from_field_value_int!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+// Wrappers decode the integer they wrap, zero being out of range for the
+// `NonZero` types.
+macro_rules! from_field_value_nonzero {
+    ( $( $t:ty => $int:ty ),* ) => {
+        $(
+            impl FromFieldValue for $t {
+                fn from_field_value(value: &FieldValue) -> Option<Self> {
+                    <$int>::from_field_value(value).and_then(<$t>::new)
+                }
+            }
+        )*
+    };
+}
+
+from_field_value_nonzero!(
+    NonZeroI8 => i8, NonZeroI16 => i16, NonZeroI32 => i32, NonZeroI64 => i64, NonZeroIsize => isize,
+    NonZeroU8 => u8, NonZeroU16 => u16, NonZeroU32 => u32, NonZeroU64 => u64, NonZeroUsize => usize
+);
+
+impl<T: FromTagValue> FromTagValue for Wrapping<T> {
+    fn from_tag_value(value: &str) -> Option<Self> {
+        T::from_tag_value(value).map(Wrapping)
+    }
+}
+
+impl<T: FromFieldValue> FromFieldValue for Wrapping<T> {
+    fn from_field_value(value: &FieldValue) -> Option<Self> {
+        T::from_field_value(value).map(Wrapping)
+    }
+}
+
+// Owned string types are decoded through a `String`.
+macro_rules! from_value_string {
+    ( $( $t:ty ),* ) => {
+        $(
+            impl FromTagValue for $t {
+                fn from_tag_value(value: &str) -> Option<Self> {
+                    Some(String::from(value).into())
+                }
+            }
+
+            impl FromFieldValue for $t {
+                fn from_field_value(value: &FieldValue) -> Option<Self> {
+                    String::from_field_value(value).map(Into::into)
+                }
+            }
+        )*
+    };
+}
+
+from_value_string!(Cow<'static, str>, Box<str>, Rc<str>, Arc<str>);
+
+// Values written through their `Display` implementation are parsed back.
+impl<T: FromStr> FromTagValue for Displayed<T> {
+    fn from_tag_value(value: &str) -> Option<Self> {
+        value.parse().ok().map(Displayed)
+    }
+}
+
+impl<T: FromStr> FromFieldValue for Displayed<T> {
+    fn from_field_value(value: &FieldValue) -> Option<Self> {
+        match value {
+            FieldValue::Str(s) => Displayed::from_tag_value(s),
+            _ => None,
+        }
+    }
+}
+
+// Floats are read back as f64, and f32 values are written with the shortest
+// representation that round trips, so narrowing here is lossless for lines
+// written from an f32.
+impl FromFieldValue for f32 {
+    fn from_field_value(value: &FieldValue) -> Option<Self> {
+        match *value {
+            FieldValue::Float32(f) => Some(f),
+            FieldValue::Float64(f) => Some(f as f32),
+            _ => None,
+        }
+    }
+}
+
+impl FromFieldValue for f64 {
+    fn from_field_value(value: &FieldValue) -> Option<Self> {
+        match *value {
+            FieldValue::Float32(f) => Some(f64::from(f)),
+            FieldValue::Float64(f) => Some(f),
+            _ => None,
+        }
+    }
+}
+
+/// Converts a line's timestamp, in `precision`, into the type of a time member.
+#[doc(hidden)]
+pub fn decode_timestamp<T: Timestamp>(ts: i64, precision: Precision) -> Result<T, DecodeError> {
+    precision.timestamp_to_nanos(ts)
+        .and_then(T::from_unix_nanos)
+        .ok_or(DecodeError::InvalidTime(ts))
+}
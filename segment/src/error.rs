@@ -0,0 +1,78 @@
+//! Errors raised while serializing metrics.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::str;
+
+/// An error encountered while serializing a metric into line protocol.
+#[derive(Debug)]
+pub enum Error {
+    /// The measurement name is empty.
+    EmptyMeasurement,
+    /// A tag or field key cannot be written, e.g. because it is empty.
+    InvalidKey(String),
+    /// A tag has an empty value; holds the tag's key.
+    EmptyTagValue(String),
+    /// The metric has no fields to write.
+    NoFields,
+    /// A float field is NaN or infinite, which line protocol cannot represent.
+    NonFiniteFloat,
+    /// An unsigned integer field is too large to be written as a signed
+    /// integer; see [`Unsigned`](crate::Unsigned).
+    UnsignedOverflow(u64),
+    /// The timestamp cannot be represented as nanoseconds since the unix
+    /// epoch in an `i64`.
+    InvalidTimestamp,
+    /// Writing to the output failed.
+    Write(io::Error),
+    /// Serialized output is not valid UTF-8.
+    InvalidUtf8(str::Utf8Error),
+}
+
+/// The result of serializing a metric.
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::EmptyMeasurement => write!(f, "empty measurement name"),
+            Error::InvalidKey(k) => write!(f, "invalid key \"{}\"", k),
+            Error::EmptyTagValue(k) => write!(f, "empty value for tag \"{}\"", k),
+            Error::NoFields => write!(f, "no fields defined for metric"),
+            Error::NonFiniteFloat => write!(f, "non-finite float field value"),
+            Error::UnsignedOverflow(u) => write!(f, "unsigned value {} exceeds i64::MAX", u),
+            Error::InvalidTimestamp => write!(f, "timestamp out of range"),
+            Error::Write(e) => write!(f, "write failed: {}", e),
+            Error::InvalidUtf8(e) => write!(f, "invalid UTF-8: {}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Write(e) => Some(e),
+            Error::InvalidUtf8(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Write(e)
+    }
+}
+
+impl From<fmt::Error> for Error {
+    fn from(_: fmt::Error) -> Self {
+        Error::Write(io::Error::other("formatter error"))
+    }
+}
+
+impl From<str::Utf8Error> for Error {
+    fn from(e: str::Utf8Error) -> Self {
+        Error::InvalidUtf8(e)
+    }
+}
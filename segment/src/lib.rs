@@ -1,61 +1,40 @@
 //! A library for serializing metric data into InfluxData's Line Protocol for
-//! ingestion into influxdb.
+//! ingestion into influxdb, and for parsing it back out again.
 
-use std::string::ToString;
-use std::time::Duration;
+use std::borrow::Cow;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::Arc;
 
 pub use segment_derive::*;
 
-#[macro_export]
-/// Serialize tag, and field, values to the provided String buffer.
-///
-/// This macro specifically handles serialization by data type and line proto
-/// type (tag vs field).
-///
-/// segment-derive uses this macro while generating the Metric::build(..)
-/// method for the metric type. During the generation of that function information
-/// about the field/tag's underlying data type is included so that optimal
-/// serialization can be performed, along with escaping for strings, etc.
-///
-/// This macro is not intended to be used outside of segment-derive, but it is
-/// not impossible to use it to create a custom metric that does not use
-/// segment-derive to generate the Metric implementation.
-macro_rules! segment_write {
-    // Type suffix for influx integers, etc.
-    ( @type_suff, $b:ident, f32, $i:ident ) => { };
-    ( @type_suff, $b:ident, f64, $i:ident ) => { };
-    ( @type_suff, $b:ident, $t:tt, tag) => {  };
-    ( @type_suff, $b:ident, $t:tt, field ) => {  $b.push('i'); };
-
-    // Determine serialization for numerics
-    ( @num_ser, f32, $b:ident, $($i:ident).+ ) => { dtoa::write(&mut $b, $($i).*)?; };
-    ( @num_ser, f64, $b:ident, $($i:ident).+ ) => { dtoa::write(&mut $b, $($i).*)?; };
-    ( @num_ser, $t:tt, $b:ident, $($i:ident).+)  =>   { itoa::write(&mut $b, $($i).*)?; };
-
-    // Serialization for string types
-    ( @str_ser, $b:ident, $val:expr, tag ) => {
-        segment::build_escapedtagstr($val, $b);
-    };
-    ( @str_ser, $b:ident, $val:expr, field ) => {
-        segment::build_escapedfieldstr($val, $b);
-    };
+mod decode;
+mod error;
+mod options;
+mod parse;
+mod point;
+mod serializer;
+mod sink;
+mod timestamp;
+mod value;
 
-    // Main Entry
-    ( $b:ident, $($i:ident).+, String, $lf:ident ) => { segment::segment_write!(@str_ser, $b, &$($i).*, $lf); };
-    ( $b:ident, $($i:ident).+, &str, $lf:ident ) => { segment::segment_write!(@str_ser, $b, $($i).*, $lf); };
-    ( $b:ident, $($i:ident).+, &'static str, $lf:ident ) => { segment::segment_write!(@str_ser, $b, $($i).*, $lf) };
-    ( $b:ident, $($i:ident).+, $t:tt, $lf:ident ) => {
-        unsafe {
-            let mut bytes = $b.as_mut_vec();
-            segment::segment_write!(@num_ser, $t, bytes, $($i).*);
-        }
-        segment::segment_write!(@type_suff, $b, $t, $lf);
-    };
-}
+pub use crate::error::{Error, Result};
+pub use crate::options::{NonFinite, Options, Precision, Unsigned};
+pub use crate::decode::{decode_timestamp, DecodeError, FromFieldValue, FromLineProto, FromTagValue};
+pub use crate::parse::{parse_line, Fields, Line, LineReader, ParseError, ParseErrorKind, Tags, Value};
+pub use crate::point::Point;
+#[doc(hidden)]
+pub use crate::serializer::DefaultTags;
+pub use crate::serializer::Serializer;
+pub use crate::sink::{LineWriter, Sink};
+pub use crate::timestamp::Timestamp;
+pub use crate::value::{Displayed, ToFieldValue, ToTagValue};
 
 /// Contains the value (and type) of a metric field.
+#[derive(Debug, Clone, PartialEq)]
 pub enum FieldValue {
     Str(String),
+    Bool(bool),
     UInt32(u32),
     UInt64(u64),
     Int32(i32),
@@ -65,66 +44,60 @@ pub enum FieldValue {
 }
 
 impl FieldValue {
-    pub fn build(&self, sb: &mut String) {
-        // NOTE: The unsafe sections below are unsafe due to manipulation of
-        //       the Vec returned by String::as_mut_vec(), since there is no
-        //       UTF8 validation. itoa, and dtoa, write in UTF8 compatible
-        //       encoding, so the unsafes are safe.
+    /// Serializes the value, with its type suffix, to the end of `sb`, using
+    /// the default [`Options`].
+    pub fn build<S: Sink + ?Sized>(&self, sb: &mut S) -> Result<bool> {
+        self.build_with(sb, &Options::default())
+    }
+
+    /// Serializes the value, with its type suffix, to the end of `sb`.
+    ///
+    /// NaN and infinite floats are handled according to `options.non_finite`,
+    /// and unsigned integers according to `options.unsigned`.
+    ///
+    /// Returns `false`, having written nothing, if the value is to be omitted.
+    pub fn build_with<S: Sink + ?Sized>(&self, sb: &mut S, options: &Options) -> Result<bool> {
         match self {
-            FieldValue::Str(s) => {
-                sb.push('"');
-                build_escapedfieldstr(s, sb);
-                sb.push('"');
-            },
-            FieldValue::UInt32(u)  => {
-                unsafe {
-                    let mut bytes = sb.as_mut_vec();
-                    let _ = itoa::write(&mut bytes, *u).expect("cannot write u32");
-                }
-                sb.push('i');
-            },
-            FieldValue::UInt64(u) => {
-                unsafe {
-                    let mut bytes = sb.as_mut_vec();
-                    let _ = itoa::write(&mut bytes, *u).expect("cannot write u64");
-                }
-                sb.push('i');
-            },
+            FieldValue::Str(s) => build_escapedfieldstr(s, sb),
+            FieldValue::Bool(b) => sb.push(if *b { 't' } else { 'f' }),
+            FieldValue::UInt32(u) => build_unsigned(u64::from(*u), sb, options.unsigned)?,
+            FieldValue::UInt64(u) => build_unsigned(*u, sb, options.unsigned)?,
             FieldValue::Int32(i) => {
-                unsafe {
-                    let mut bytes = sb.as_mut_vec();
-                    let _ = itoa::write(&mut bytes, *i).expect("cannot write i32");
-                }
+                build_integer(*i, sb);
                 sb.push('i');
             },
             FieldValue::Int64(i) => {
-                unsafe {
-                    let mut bytes = sb.as_mut_vec();
-                    let _ = itoa::write(&mut bytes, *i).expect("cannot write i64");
-                }
+                build_integer(*i, sb);
                 sb.push('i');
             },
-            FieldValue::Float32(fl) => {
-                unsafe {
-                    let mut bytes = sb.as_mut_vec();
-                    let _ = dtoa::write(&mut bytes, *fl).expect("cannot write f32");
-                }
+            FieldValue::Float32(fl) => match options.non_finite.apply_f32(*fl)? {
+                Some(fl) => build_float(fl, sb)?,
+                None => return Ok(false),
+            },
+            FieldValue::Float64(fl) => match options.non_finite.apply_f64(*fl)? {
+                Some(fl) => build_float(fl, sb)?,
+                None => return Ok(false),
             },
-            FieldValue::Float64(fl) => {
-                unsafe {
-                    let mut bytes = sb.as_mut_vec();
-                    let _ = dtoa::write(&mut bytes, *fl).expect("cannot write f64");
-                }
-            }
         };
+        Ok(true)
     }
 }
 
-impl ToString for FieldValue {
-    fn to_string(&self) -> String {
-        let mut sret = String::new();
-        self.build(&mut sret);
-        sret
+/// Formats the value as it is written in a line, with unsigned integers
+/// written with the `u` suffix whatever their size. NaN and infinite floats,
+/// which cannot be written, are formatted as Rust formats them.
+impl fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FieldValue::Float32(fl) if !fl.is_finite() => fmt::Display::fmt(fl, f),
+            FieldValue::Float64(fl) if !fl.is_finite() => fmt::Display::fmt(fl, f),
+            _ => {
+                let mut sret = String::new();
+                self.build_with(&mut sret, &Options::default().unsigned(Unsigned::Native))
+                    .map_err(|_| fmt::Error)?;
+                f.write_str(&sret)
+            },
+        }
     }
 }
 
@@ -142,6 +115,41 @@ impl From<&str> for FieldValue {
     }
 }
 
+impl From<Cow<'_, str>> for FieldValue {
+    #[inline]
+    fn from(item: Cow<'_, str>) -> Self {
+        FieldValue::Str(item.into_owned())
+    }
+}
+
+impl From<Box<str>> for FieldValue {
+    #[inline]
+    fn from(item: Box<str>) -> Self {
+        FieldValue::Str(item.into())
+    }
+}
+
+impl From<Rc<str>> for FieldValue {
+    #[inline]
+    fn from(item: Rc<str>) -> Self {
+        FieldValue::Str(String::from(&*item))
+    }
+}
+
+impl From<Arc<str>> for FieldValue {
+    #[inline]
+    fn from(item: Arc<str>) -> Self {
+        FieldValue::Str(String::from(&*item))
+    }
+}
+
+impl From<bool> for FieldValue {
+    #[inline]
+    fn from(item: bool) -> Self {
+        FieldValue::Bool(item)
+    }
+}
+
 impl From<u32> for FieldValue {
     #[inline]
     fn from(item: u32) -> Self {
@@ -156,6 +164,20 @@ impl From<u64> for FieldValue {
     }
 }
 
+impl From<i32> for FieldValue {
+    #[inline]
+    fn from(item: i32) -> Self {
+        FieldValue::Int32(item)
+    }
+}
+
+impl From<i64> for FieldValue {
+    #[inline]
+    fn from(item: i64) -> Self {
+        FieldValue::Int64(item)
+    }
+}
+
 impl From<f32> for FieldValue {
     #[inline]
     fn from(item: f32) -> Self {
@@ -170,7 +192,20 @@ impl From<f64> for FieldValue {
     }
 }
 
+impl From<Value<'_>> for FieldValue {
+    fn from(item: Value<'_>) -> Self {
+        match item {
+            Value::Str(s) => FieldValue::Str(s.into_owned()),
+            Value::Bool(b) => FieldValue::Bool(b),
+            Value::Int(i) => FieldValue::Int64(i),
+            Value::UInt(u) => FieldValue::UInt64(u),
+            Value::Float(f) => FieldValue::Float64(f),
+        }
+    }
+}
+
 /// A key/value pair destined for becoming a Line Protocol Field.
+#[derive(Debug, Clone, PartialEq)]
 pub struct Field {
     /// The name or key of the field.
     pub name: String,
@@ -179,6 +214,7 @@ pub struct Field {
 }
 
 /// A key/value pair destined for becoming a Line Protocol Tag.
+#[derive(Debug, Clone, PartialEq)]
 pub struct Tag {
     /// The name/key of the tag.
     pub name: String,
@@ -186,29 +222,165 @@ pub struct Tag {
     pub value: String,
 }
 
-
 /// A metric represents a single point in a measurement.
 pub trait Metric {
-    fn time(&self) -> Duration;
+    /// The time of the metric, in nanoseconds since the unix epoch, or `None`
+    /// to let the server assign one.
+    fn time(&self) -> Option<i64>;
     fn measurement(&self) -> String;
     fn fields(&self) -> Vec<Field>;
     fn tags(&self) -> Vec<Tag>;
-    fn to_lineproto(&self) -> String;
-    fn build(&self, buffer: &mut String) -> std::io::Result<usize>;
+
+    /// Serializes the metric into a newly allocated line.
+    fn to_lineproto(&self) -> Result<String> {
+        let mut s = String::with_capacity(64);
+        self.build(&mut s)?;
+        Ok(s)
+    }
+
+    /// Appends the metric, as a line of line protocol, to `buffer`, using the
+    /// default [`Options`].
+    fn build(&self, buffer: &mut dyn Sink) -> Result<usize> {
+        self.build_with(buffer, &Options::default())
+    }
+
+    /// Appends the metric, as a line of line protocol, to `buffer`.
+    ///
+    /// Returns the number of bytes written, which is zero if every field was
+    /// omitted (e.g. by the [`NonFinite::Skip`] policy) and so no line could
+    /// be written. On error `buffer` is left as it was before the call.
+    fn build_with(&self, buffer: &mut dyn Sink, options: &Options) -> Result<usize>;
+
+    /// Appends the metric, as a line of line protocol, to `buffer`, with the
+    /// `defaults` tags, sorted by name, merged into its own; used by
+    /// [`Serializer`]. The metric's own tags take precedence over defaults
+    /// with the same key.
+    ///
+    /// The default implementation calls [`build_with`](Metric::build_with)
+    /// when there are no `defaults`, and otherwise writes a [`Point`] made of
+    /// the metric's measurement, tags, fields and time, so that any metric can
+    /// be written with default tags. Derived metrics merge them as they are
+    /// written.
+    fn build_with_tags(&self, buffer: &mut dyn Sink, options: &Options, defaults: &[Tag]) -> Result<usize> {
+        if defaults.is_empty() {
+            return self.build_with(buffer, options);
+        }
+        let mut tags = self.tags();
+        for tag in defaults {
+            if !tags.iter().any(|t| t.name == tag.name) {
+                tags.push(tag.clone());
+            }
+        }
+        let mut point = Point::new(self.measurement());
+        point.tags = tags;
+        point.fields = self.fields();
+        point.timestamp = self.time();
+        point.build_with(buffer, options)
+    }
+}
+
+/// A group of tags and fields, shared by several metrics, that is merged into
+/// a metric's line by marking the member holding it `#[segment(flatten)]`.
+///
+/// Implemented with `#[derive(Flatten)]`, on a struct whose members are
+/// marked as tags and fields like those of a metric. The merged tags are
+/// sorted along with the metric's own, leaving out any whose key the metric
+/// or an earlier group already has, while the group's fields follow the
+/// metric's own fields.
+pub trait Flatten {
+    /// Appends the group's tags to `tags`.
+    fn flatten_tags(&self, tags: &mut Vec<Tag>);
+
+    /// Appends the group's fields to `fields`.
+    fn flatten_fields(&self, fields: &mut Vec<Field>);
+
+    /// Writes the group's fields to the end of `buffer`, whose field set starts
+    /// at `fields_start`. Every field but the first of the set is preceded by a
+    /// comma.
+    fn write_fields(&self, buffer: &mut dyn Sink, fields_start: usize, options: &Options) -> Result<()>;
+}
+
+/// An absent group adds no tags or fields.
+impl<T: Flatten> Flatten for Option<T> {
+    fn flatten_tags(&self, tags: &mut Vec<Tag>) {
+        if let Some(group) = self {
+            group.flatten_tags(tags);
+        }
+    }
+
+    fn flatten_fields(&self, fields: &mut Vec<Field>) {
+        if let Some(group) = self {
+            group.flatten_fields(fields);
+        }
+    }
+
+    fn write_fields(&self, buffer: &mut dyn Sink, fields_start: usize, options: &Options) -> Result<()> {
+        match self {
+            Some(group) => group.write_fields(buffer, fields_start, options),
+            None => Ok(()),
+        }
+    }
+}
+
+macro_rules! flatten_deref {
+    ( $( $t:ty ),* ) => {
+        $(
+            impl<T: Flatten + ?Sized> Flatten for $t {
+                fn flatten_tags(&self, tags: &mut Vec<Tag>) {
+                    (**self).flatten_tags(tags)
+                }
+
+                fn flatten_fields(&self, fields: &mut Vec<Field>) {
+                    (**self).flatten_fields(fields)
+                }
+
+                fn write_fields(&self, buffer: &mut dyn Sink, fields_start: usize, options: &Options) -> Result<()> {
+                    (**self).write_fields(buffer, fields_start, options)
+                }
+            }
+        )*
+    };
+}
+
+flatten_deref!(&T, Box<T>, Rc<T>, Arc<T>);
+
+/// Writes an unsigned integer field value, with its type suffix, to `buff`.
+#[doc(hidden)]
+pub fn build_unsigned<S: Sink + ?Sized>(value: u64, buff: &mut S, mode: Unsigned) -> Result<()> {
+    let (value, suffix) = match mode {
+        Unsigned::Native => (value, 'u'),
+        Unsigned::Clamp => (value.min(i64::MAX as u64), 'i'),
+        Unsigned::Error if value > i64::MAX as u64 => return Err(Error::UnsignedOverflow(value)),
+        Unsigned::Error => (value, 'i'),
+    };
+    build_integer(value, buff);
+    buff.push(suffix);
+    Ok(())
+}
+
+/// Writes an integer, in decimal, to `buff`.
+#[doc(hidden)]
+pub fn build_integer<I: itoa::Integer, S: Sink + ?Sized>(value: I, buff: &mut S) {
+    buff.push_str(itoa::Buffer::new().format(value));
+}
+
+/// Writes a float, in its shortest representation that round trips, to `buff`.
+fn build_float<V: dtoa::Floating, S: Sink + ?Sized>(value: V, buff: &mut S) -> Result<()> {
+    let mut bytes = [0u8; 32];
+    let len = dtoa::write(&mut bytes[..], value)?;
+    buff.push_str(std::str::from_utf8(&bytes[..len])?);
+    Ok(())
 }
 
 // measurement[,tag=val[,tag=val]] field=value[,field=value]
 
-/// Escapes the provided tag value `s` and adds the newly escaped values to `buff`.
+/// Escapes every character of `s` matched by `escaped`, or a newline, and adds
+/// the result to `buff`.
 /// > NOTE: Source for this is an adaptation from std::String::replace
-pub fn build_escapedtagstr(s: &str, buff: &mut String) {
+fn build_escaped<F: Fn(char) -> bool, S: Sink + ?Sized>(s: &str, buff: &mut S, escaped: F) {
     let mut last_end = 0;
-    let matcher = |c: char| match c {
-        '\n' | '"' | '.' | ' ' | '=' => true,
-        _ => false,
-    };
-    for (start, part) in s.match_indices(matcher) {
-        buff.push_str(unsafe { s.get_unchecked(last_end..start) });
+    for (start, part) in s.match_indices(|c: char| c == '\n' || escaped(c)) {
+        buff.push_str(&s[last_end..start]);
         match part {
             "\n" => buff.push_str("\\n"),
             _ => {
@@ -218,33 +390,44 @@ pub fn build_escapedtagstr(s: &str, buff: &mut String) {
         }
         last_end = start + part.len();
     }
-    buff.push_str(unsafe { s.get_unchecked(last_end..s.len()) });
+    buff.push_str(&s[last_end..]);
 }
 
-/// Escapes the provided field string value `s` and adds the newly escaped values to `buff`.
-/// > NOTE: Source for this is an adaptation from std::String::replace
-pub fn build_escapedfieldstr(s: &str, buff: &mut String) {
-    let mut last_end = 0;
-    let matcher = |c: char| match c {
-        '\n' | '"' => true,
-        _ => false,
+/// Escapes the provided measurement name `s` and adds the newly escaped values to `buff`.
+///
+/// Commas, spaces and backslashes are escaped with a backslash, newlines are
+/// written as `\n`.
+pub fn build_escapedmeasurement<S: Sink + ?Sized>(s: &str, buff: &mut S) {
+    build_escaped(s, buff, |c| matches!(c, ',' | ' ' | '\\'));
+}
 
-    };
+/// Escapes the provided tag key, tag value, or field key `s` and adds the newly
+/// escaped values to `buff`.
+///
+/// Commas, equals signs, spaces and backslashes are escaped with a backslash,
+/// newlines are written as `\n`.
+pub fn build_escapedtagstr<S: Sink + ?Sized>(s: &str, buff: &mut S) {
+    build_escaped(s, buff, |c| matches!(c, ',' | '=' | ' ' | '\\'));
+}
 
+/// Escapes the provided field string value `s` and adds the newly escaped,
+/// and quoted, value to `buff`.
+///
+/// Double quotes and backslashes are escaped with a backslash, newlines are
+/// written as `\n`.
+pub fn build_escapedfieldstr<S: Sink + ?Sized>(s: &str, buff: &mut S) {
     buff.push('"');
-    for (start, part) in s.match_indices(matcher) {
-        buff.push_str(unsafe { s.get_unchecked(last_end..start) });
-        match part {
-            "\n" => buff.push_str("\\n"),
-            "\"" => buff.push_str("\\\""),
-            _ => (),
-        }
-        last_end = start + part.len();
-    }
-    buff.push_str(unsafe { s.get_unchecked(last_end..s.len()) });
+    build_escaped(s, buff, |c| matches!(c, '"' | '\\'));
     buff.push('"')
 }
 
+/// Returns a new string, with the measurement escaped version of `s`.
+pub fn escape_measurement(s: &str) -> String {
+    let mut new_s = String::with_capacity(s.len()+16);
+    build_escapedmeasurement(s, &mut new_s);
+    new_s
+}
+
 /// Returns a new string, with the tag escaped version of `s`.
 pub fn escape_tagstr(s: &str) -> String {
     let mut new_s = String::with_capacity(s.len()+16);
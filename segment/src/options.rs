@@ -0,0 +1,160 @@
+//! Runtime options controlling serialization.
+
+use crate::{Error, Result};
+
+/// What to do with a float field that is NaN or infinite, neither of which
+/// line protocol can represent.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum NonFinite {
+    /// Omit the field from the line. If every field of a line is omitted, the
+    /// line is not written at all.
+    #[default]
+    Skip,
+    /// Fail with [`Error::NonFiniteFloat`], writing nothing.
+    Error,
+    /// Write the given sentinel value in place of the non-finite value. A
+    /// sentinel that is itself non-finite is treated as `Error`.
+    Substitute(f64),
+}
+
+impl NonFinite {
+    /// Applies the policy to `value`, returning the value to write, or `None`
+    /// if the field should be omitted.
+    pub fn apply_f64(self, value: f64) -> Result<Option<f64>> {
+        if value.is_finite() {
+            return Ok(Some(value));
+        }
+        match self {
+            NonFinite::Skip => Ok(None),
+            NonFinite::Error => Err(Error::NonFiniteFloat),
+            NonFinite::Substitute(sentinel) if sentinel.is_finite() => Ok(Some(sentinel)),
+            NonFinite::Substitute(_) => Err(Error::NonFiniteFloat),
+        }
+    }
+
+    /// Applies the policy to `value`, returning the value to write, or `None`
+    /// if the field should be omitted. A sentinel is narrowed to `f32`.
+    pub fn apply_f32(self, value: f32) -> Result<Option<f32>> {
+        if value.is_finite() {
+            return Ok(Some(value));
+        }
+        match self {
+            NonFinite::Skip => Ok(None),
+            NonFinite::Error => Err(Error::NonFiniteFloat),
+            NonFinite::Substitute(sentinel) if (sentinel as f32).is_finite() => Ok(Some(sentinel as f32)),
+            NonFinite::Substitute(_) => Err(Error::NonFiniteFloat),
+        }
+    }
+}
+
+/// How unsigned integer fields are written.
+///
+/// InfluxDB 1.8+ and 2.x accept unsigned integers with a `u` suffix, while
+/// older servers only accept signed, `i` suffixed, 64-bit integers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Unsigned {
+    /// Write unsigned integers with the `u` suffix, e.g. `123u`.
+    Native,
+    /// Write signed integers, clamping values above `i64::MAX` to `i64::MAX`.
+    Clamp,
+    /// Write signed integers, failing with [`Error::UnsignedOverflow`] for
+    /// values above `i64::MAX`.
+    #[default]
+    Error,
+}
+
+/// The unit of line timestamps, matching the `precision` parameter of the
+/// InfluxDB write endpoint.
+///
+/// Timestamps are truncated towards the past when converted to a coarser
+/// precision: `1.999s` is written as `1` in `Seconds`, and `-1.5s`, i.e. half
+/// a second before `-1s`, as `-2`. A point is never moved into the future.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Precision {
+    /// Nanoseconds, `ns`.
+    #[default]
+    Nanoseconds,
+    /// Microseconds, `us`.
+    Microseconds,
+    /// Milliseconds, `ms`.
+    Milliseconds,
+    /// Seconds, `s`.
+    Seconds,
+}
+
+impl Precision {
+    /// The value of the `precision` query parameter for this precision.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Precision::Nanoseconds => "ns",
+            Precision::Microseconds => "us",
+            Precision::Milliseconds => "ms",
+            Precision::Seconds => "s",
+        }
+    }
+
+    fn nanos_per_unit(self) -> u64 {
+        match self {
+            Precision::Nanoseconds => 1,
+            Precision::Microseconds => 1_000,
+            Precision::Milliseconds => 1_000_000,
+            Precision::Seconds => 1_000_000_000,
+        }
+    }
+
+    /// Converts a timestamp in nanoseconds into this precision, rounding
+    /// towards negative infinity.
+    pub fn nanos_to_timestamp(self, nanos: i64) -> i64 {
+        nanos.div_euclid(self.nanos_per_unit() as i64)
+    }
+
+    /// Converts a timestamp in this precision into nanoseconds, or `None` if
+    /// it overflows.
+    pub fn timestamp_to_nanos(self, timestamp: i64) -> Option<i64> {
+        timestamp.checked_mul(self.nanos_per_unit() as i64)
+    }
+}
+
+/// Options controlling how metrics are serialized, passed to
+/// [`Metric::build_with`](crate::Metric::build_with).
+///
+/// ```
+/// use segment::{NonFinite, Options};
+///
+/// let options = Options::default().non_finite(NonFinite::Error);
+/// assert_eq!(options.non_finite, NonFinite::Error);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Options {
+    /// What to do with NaN and infinite float fields.
+    pub non_finite: NonFinite,
+    /// How to write unsigned integer fields, unless a member is declared
+    /// with `#[segment(field, unsigned)]`, which always uses `Unsigned::Native`.
+    pub unsigned: Unsigned,
+    /// The precision of written timestamps. When set, this overrides the
+    /// precision declared with `#[segment(time, precision = "...")]`, and
+    /// the timestamps of a [`Point`](crate::Point), taken as nanoseconds,
+    /// are converted to it. When unset, derived metrics use their declared
+    /// precision, nanoseconds by default, and points are written as is.
+    pub precision: Option<Precision>,
+}
+
+impl Options {
+    /// Sets the policy for NaN and infinite float fields.
+    pub fn non_finite(mut self, policy: NonFinite) -> Options {
+        self.non_finite = policy;
+        self
+    }
+
+    /// Sets how unsigned integer fields are written.
+    pub fn unsigned(mut self, mode: Unsigned) -> Options {
+        self.unsigned = mode;
+        self
+    }
+
+    /// Sets the precision of written timestamps.
+    pub fn precision(mut self, precision: Precision) -> Options {
+        self.precision = Some(precision);
+        self
+    }
+}
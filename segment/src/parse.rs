@@ -0,0 +1,505 @@
+//! Zero-copy parsing of InfluxData's Line Protocol.
+//!
+//! [`parse_line`] validates a single line and returns a [`Line`], a borrowed
+//! view over the input. Measurement, keys and string values are only copied
+//! when they contain escape sequences that need to be removed; tags and
+//! fields are decoded lazily as they are iterated, so inspecting a line does
+//! not allocate.
+//!
+//! [`LineReader`] parses a stream of lines from any [`BufRead`] into owned
+//! [`Point`]s, reporting errors per line so a single bad line does not stop
+//! the rest of a batch from being read.
+
+use std::borrow::Cow;
+use std::error;
+use std::fmt;
+use std::io::{self, BufRead};
+use std::str;
+
+use crate::Point;
+
+/// The kind of problem encountered while parsing a line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// The line has no measurement name.
+    EmptyMeasurement,
+    /// A tag has an empty key, or no `=` separating key and value.
+    InvalidTag,
+    /// A tag has a key but no value.
+    EmptyTagValue,
+    /// The line has no field set.
+    MissingFields,
+    /// A field has an empty key, or no `=` separating key and value.
+    InvalidField,
+    /// A field value could not be decoded as any supported type.
+    InvalidFieldValue,
+    /// A string field value is missing its closing quote.
+    UnterminatedString,
+    /// The timestamp is not a valid signed 64-bit integer.
+    InvalidTimestamp,
+    /// Unexpected characters follow the end of the line.
+    TrailingCharacters,
+    /// The line is not valid UTF-8.
+    InvalidUtf8,
+    /// The underlying reader failed; no further lines will be read.
+    Io(io::ErrorKind),
+}
+
+impl ParseErrorKind {
+    fn description(self) -> &'static str {
+        match self {
+            ParseErrorKind::EmptyMeasurement => "missing measurement name",
+            ParseErrorKind::InvalidTag => "invalid tag, expected key=value",
+            ParseErrorKind::EmptyTagValue => "missing tag value",
+            ParseErrorKind::MissingFields => "missing field set",
+            ParseErrorKind::InvalidField => "invalid field, expected key=value",
+            ParseErrorKind::InvalidFieldValue => "invalid field value",
+            ParseErrorKind::UnterminatedString => "unterminated string field value",
+            ParseErrorKind::InvalidTimestamp => "invalid timestamp",
+            ParseErrorKind::TrailingCharacters => "unexpected characters after timestamp",
+            ParseErrorKind::InvalidUtf8 => "invalid UTF-8",
+            ParseErrorKind::Io(_) => "read error",
+        }
+    }
+}
+
+/// An error encountered while parsing a line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// What went wrong.
+    pub kind: ParseErrorKind,
+    /// The 1-based line number of the offending line.
+    pub line: usize,
+    /// Byte offset into the line where the problem was found.
+    pub column: usize,
+}
+
+impl ParseError {
+    fn new(kind: ParseErrorKind, column: usize) -> ParseError {
+        ParseError { kind, line: 1, column }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ParseErrorKind::Io(kind) =>
+                write!(f, "read error after line {}: {:?}", self.line, kind),
+            _ =>
+                write!(f, "{} at line {}, column {}", self.kind.description(), self.line, self.column),
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
+/// A decoded field value, borrowing from the parsed line where possible.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value<'a> {
+    /// A string value, unescaped.
+    Str(Cow<'a, str>),
+    /// A boolean value (`t`, `true`, `F`, `False`, ...).
+    Bool(bool),
+    /// A signed integer value (`42i`).
+    Int(i64),
+    /// An unsigned integer value (`42u`), or an `i` suffixed integer too
+    /// large for an `i64`.
+    UInt(u64),
+    /// A floating point value (`42.0`, `42`, `4.2e1`).
+    Float(f64),
+}
+
+/// A borrowed view of a single, validated, line of line protocol.
+#[derive(Debug, Clone)]
+pub struct Line<'a> {
+    measurement: Cow<'a, str>,
+    tags: &'a str,
+    fields: &'a str,
+    timestamp: Option<i64>,
+}
+
+impl<'a> Line<'a> {
+    /// The unescaped measurement name.
+    pub fn measurement(&self) -> &str {
+        &self.measurement
+    }
+
+    /// Iterates over the tag set, in the order it appears in the line.
+    pub fn tags(&self) -> Tags<'a> {
+        Tags { cursor: Cursor::new(self.tags) }
+    }
+
+    /// Iterates over the field set, in the order it appears in the line.
+    pub fn fields(&self) -> Fields<'a> {
+        Fields { cursor: Cursor::new(self.fields) }
+    }
+
+    /// The timestamp of the line, if one was present.
+    pub fn timestamp(&self) -> Option<i64> {
+        self.timestamp
+    }
+
+    /// Returns the value of the first tag named `key`.
+    pub fn tag(&self, key: &str) -> Option<Cow<'a, str>> {
+        self.tags().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    /// Returns the value of the first field named `key`.
+    pub fn field(&self, key: &str) -> Option<Value<'a>> {
+        self.fields().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+}
+
+/// Iterator over the tags of a [`Line`], yielding unescaped `(key, value)` pairs.
+#[derive(Debug, Clone)]
+pub struct Tags<'a> {
+    cursor: Cursor<'a>,
+}
+
+impl<'a> Iterator for Tags<'a> {
+    type Item = (Cow<'a, str>, Cow<'a, str>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cursor.at_end() {
+            return None;
+        }
+        // The section was validated by parse_line, so the unwraps cannot fail.
+        let tag = self.cursor.tag().unwrap();
+        self.cursor.eat(b',');
+        Some(tag)
+    }
+}
+
+/// Iterator over the fields of a [`Line`], yielding unescaped keys and decoded values.
+#[derive(Debug, Clone)]
+pub struct Fields<'a> {
+    cursor: Cursor<'a>,
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = (Cow<'a, str>, Value<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cursor.at_end() {
+            return None;
+        }
+        // The section was validated by parse_line, so the unwraps cannot fail.
+        let field = self.cursor.field().unwrap();
+        self.cursor.eat(b',');
+        Some(field)
+    }
+}
+
+/// Parses a single line of line protocol.
+///
+/// A trailing `\n` (or `\r\n`) is ignored. Escape sequences are the inverse of
+/// those produced by [`build_escapedmeasurement`](crate::build_escapedmeasurement),
+/// [`build_escapedtagstr`](crate::build_escapedtagstr) and
+/// [`build_escapedfieldstr`](crate::build_escapedfieldstr): a backslash before
+/// a character that must be escaped in that context yields the character, and
+/// `\n` yields a newline. Any other backslash is kept as is.
+pub fn parse_line(line: &str) -> Result<Line<'_>, ParseError> {
+    let line = line.strip_suffix('\n').unwrap_or(line);
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    let mut cursor = Cursor::new(line);
+
+    let measurement = cursor.take_until(b", ");
+    if measurement.is_empty() {
+        return Err(ParseError::new(ParseErrorKind::EmptyMeasurement, 0));
+    }
+
+    let tags_start = cursor.pos + 1;
+    while cursor.eat(b',') {
+        cursor.tag()?;
+    }
+    let tags = if cursor.pos > tags_start { &line[tags_start..cursor.pos] } else { "" };
+
+    if !cursor.eat(b' ') || cursor.at_end() {
+        return Err(ParseError::new(ParseErrorKind::MissingFields, cursor.pos));
+    }
+    let fields_start = cursor.pos;
+    loop {
+        cursor.field()?;
+        if !cursor.eat(b',') {
+            break;
+        }
+    }
+    let fields = &line[fields_start..cursor.pos];
+
+    let timestamp = if cursor.eat(b' ') {
+        let start = cursor.pos;
+        let raw = cursor.take_until(b" ");
+        // `i64::from_str` accepts a leading `+`, which line protocol does not.
+        match raw.parse::<i64>() {
+            Ok(ts) if !raw.starts_with('+') => Some(ts),
+            _ => return Err(ParseError::new(ParseErrorKind::InvalidTimestamp, start)),
+        }
+    } else {
+        None
+    };
+
+    if !cursor.at_end() {
+        return Err(ParseError::new(ParseErrorKind::TrailingCharacters, cursor.pos));
+    }
+
+    Ok(Line {
+        measurement: unescape_measurement(measurement),
+        tags,
+        fields,
+        timestamp,
+    })
+}
+
+/// Parses line protocol, one line at a time, from a [`BufRead`].
+///
+/// Blank lines and comments (lines starting with `#`) are skipped, as is any
+/// leading whitespace. Each remaining line yields either a [`Point`] or a
+/// [`ParseError`] carrying the line number and column; parsing resumes with
+/// the next line after an error. A failure of the underlying reader is
+/// reported once as [`ParseErrorKind::Io`] and ends the iteration.
+pub struct LineReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+    line: usize,
+    done: bool,
+}
+
+impl<R: BufRead> LineReader<R> {
+    /// Creates a new reader over `reader`.
+    pub fn new(reader: R) -> LineReader<R> {
+        LineReader {
+            reader,
+            buf: Vec::with_capacity(256),
+            line: 0,
+            done: false,
+        }
+    }
+
+    /// The number of the last line read, starting at 1.
+    pub fn line_number(&self) -> usize {
+        self.line
+    }
+
+    /// Unwraps this `LineReader`, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: BufRead> Iterator for LineReader<R> {
+    type Item = Result<Point, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            self.buf.clear();
+            match self.reader.read_until(b'\n', &mut self.buf) {
+                Ok(0) => self.done = true,
+                Ok(_) => {
+                    self.line += 1;
+                    if let Some(result) = parse_buffered(&self.buf, self.line) {
+                        return Some(result);
+                    }
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(ParseError {
+                        kind: ParseErrorKind::Io(e.kind()),
+                        line: self.line,
+                        column: 0,
+                    }));
+                },
+            }
+        }
+        None
+    }
+}
+
+/// Parses one raw line read by a `LineReader`, or returns `None` if it should be skipped.
+fn parse_buffered(buf: &[u8], line_number: usize) -> Option<Result<Point, ParseError>> {
+    let text = match str::from_utf8(buf) {
+        Ok(text) => text,
+        Err(e) => return Some(Err(ParseError {
+            kind: ParseErrorKind::InvalidUtf8,
+            line: line_number,
+            column: e.valid_up_to(),
+        })),
+    };
+
+    let trimmed = text.trim_start_matches([' ', '\t']);
+    if trimmed.trim_end().is_empty() || trimmed.starts_with('#') {
+        return None;
+    }
+
+    let indent = text.len() - trimmed.len();
+    Some(parse_line(trimmed).map(Point::from).map_err(|e| ParseError {
+        line: line_number,
+        column: e.column + indent,
+        ..e
+    }))
+}
+
+/// Position within a line, or a section of one.
+#[derive(Debug, Clone)]
+struct Cursor<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(src: &'a str) -> Cursor<'a> {
+        Cursor { src, pos: 0 }
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.src.len()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.src.as_bytes().get(self.pos).cloned()
+    }
+
+    /// Consumes `b` if it is the next byte.
+    fn eat(&mut self, b: u8) -> bool {
+        if self.peek() == Some(b) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Consumes input up to, but not including, the first unescaped byte in `stops`.
+    ///
+    /// All stop bytes are ASCII, so the returned slice always falls on a char
+    /// boundary even when an escaped character is multi-byte.
+    fn take_until(&mut self, stops: &[u8]) -> &'a str {
+        let bytes = self.src.as_bytes();
+        let start = self.pos;
+        while self.pos < bytes.len() {
+            let b = bytes[self.pos];
+            if b == b'\\' {
+                self.pos = (self.pos + 2).min(bytes.len());
+            } else if stops.contains(&b) {
+                break;
+            } else {
+                self.pos += 1;
+            }
+        }
+        &self.src[start..self.pos]
+    }
+
+    fn tag(&mut self) -> Result<(Cow<'a, str>, Cow<'a, str>), ParseError> {
+        let start = self.pos;
+        let key = self.take_until(b"=, ");
+        if key.is_empty() || !self.eat(b'=') {
+            return Err(ParseError::new(ParseErrorKind::InvalidTag, start));
+        }
+        let value_start = self.pos;
+        let value = self.take_until(b", ");
+        if value.is_empty() {
+            return Err(ParseError::new(ParseErrorKind::EmptyTagValue, value_start));
+        }
+        Ok((unescape(key), unescape(value)))
+    }
+
+    fn field(&mut self) -> Result<(Cow<'a, str>, Value<'a>), ParseError> {
+        let start = self.pos;
+        let key = self.take_until(b"=, ");
+        if key.is_empty() || !self.eat(b'=') {
+            return Err(ParseError::new(ParseErrorKind::InvalidField, start));
+        }
+        let value_start = self.pos;
+        let value = if self.eat(b'"') {
+            let raw = self.take_until(b"\"");
+            if !self.eat(b'"') {
+                return Err(ParseError::new(ParseErrorKind::UnterminatedString, value_start));
+            }
+            Value::Str(unescape_str(raw))
+        } else {
+            let raw = self.take_until(b", ");
+            parse_value(raw)
+                .ok_or_else(|| ParseError::new(ParseErrorKind::InvalidFieldValue, value_start))?
+        };
+        Ok((unescape(key), value))
+    }
+}
+
+/// Decodes a non-string field value.
+fn parse_value(raw: &str) -> Option<Value<'static>> {
+    if let Some(b) = parse_bool(raw) {
+        return Some(Value::Bool(b));
+    }
+    // Numbers never have a leading plus sign, which str::parse accepts.
+    if raw.starts_with('+') {
+        return None;
+    }
+    if let Some(digits) = raw.strip_suffix('u') {
+        return digits.parse().ok().map(Value::UInt);
+    }
+    if let Some(digits) = raw.strip_suffix('i') {
+        // Unsigned values are written with the `i` suffix too, so fall back
+        // to u64 for anything above i64::MAX.
+        return match digits.parse() {
+            Ok(i) => Some(Value::Int(i)),
+            Err(_) => digits.parse().ok().map(Value::UInt),
+        };
+    }
+    // Reject the spellings of NaN and infinity that str::parse accepts.
+    match raw.as_bytes().first() {
+        Some(b'0'..=b'9') | Some(b'-') | Some(b'.') => (),
+        _ => return None,
+    }
+    match raw.parse::<f64>() {
+        Ok(f) if f.is_finite() => Some(Value::Float(f)),
+        _ => None,
+    }
+}
+
+/// Decodes any of the spellings line protocol accepts for a boolean.
+pub(crate) fn parse_bool(raw: &str) -> Option<bool> {
+    match raw {
+        "t" | "T" | "true" | "True" | "TRUE" => Some(true),
+        "f" | "F" | "false" | "False" | "FALSE" => Some(false),
+        _ => None,
+    }
+}
+
+/// Removes escapes from a measurement name.
+fn unescape_measurement(raw: &str) -> Cow<'_, str> {
+    unescape_with(raw, |c| matches!(c, ',' | ' ' | '\\'))
+}
+
+/// Removes escapes from a tag key, tag value or field key.
+fn unescape(raw: &str) -> Cow<'_, str> {
+    unescape_with(raw, |c| matches!(c, ',' | '=' | ' ' | '\\'))
+}
+
+/// Removes escapes from the contents of a string field value.
+fn unescape_str(raw: &str) -> Cow<'_, str> {
+    unescape_with(raw, |c| matches!(c, '"' | '\\'))
+}
+
+fn unescape_with<F: Fn(char) -> bool>(raw: &str, escaped: F) -> Cow<'_, str> {
+    if !raw.contains('\\') {
+        return Cow::Borrowed(raw);
+    }
+    let mut s = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            s.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => s.push('\n'),
+            Some(e) if escaped(e) => s.push(e),
+            Some(e) => {
+                s.push('\\');
+                s.push(e);
+            },
+            None => s.push('\\'),
+        }
+    }
+    Cow::Owned(s)
+}
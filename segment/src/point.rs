@@ -0,0 +1,211 @@
+//! An owned, dynamically built, point.
+
+use crate::{build_escapedmeasurement, build_escapedtagstr, build_integer, Error, Field, FieldValue, Line, Metric, Options, Result, Sink, Tag, Timestamp};
+
+/// An owned point, either decoded from a line of Line Protocol or built at
+/// runtime for metrics whose schema is not known at compile time.
+///
+/// ```
+/// use segment::{Metric, Point};
+///
+/// let point = Point::new("cpu")
+///     .tag("host", "localhost")
+///     .field("value", 42.0)
+///     .timestamp(std::time::Duration::from_nanos(0));
+///
+/// assert_eq!(point.to_lineproto().unwrap(), "cpu,host=localhost value=42.0 0");
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point {
+    /// The name of the measurement.
+    pub measurement: String,
+    /// The tags of the point, in the order they were read or added.
+    pub tags: Vec<Tag>,
+    /// The fields of the point, in the order they were read or added.
+    pub fields: Vec<Field>,
+    /// The timestamp of the point, if present, in the precision it was written.
+    /// Timestamps set through [`Point::timestamp`] are in nanoseconds.
+    pub timestamp: Option<i64>,
+}
+
+impl Point {
+    /// Creates a point for `measurement`, with no tags, fields or timestamp.
+    pub fn new<S: Into<String>>(measurement: S) -> Point {
+        Point {
+            measurement: measurement.into(),
+            tags: Vec::new(),
+            fields: Vec::new(),
+            timestamp: None,
+        }
+    }
+
+    /// Adds a tag, keeping the tags sorted by name, or replaces the value of
+    /// the tag with the same name.
+    pub fn tag<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Point {
+        let tag = Tag { name: name.into(), value: value.into() };
+        match self.tags.iter_mut().find(|t| t.name == tag.name) {
+            Some(existing) => existing.value = tag.value,
+            None => {
+                let idx = self.tags.iter().position(|t| t.name > tag.name).unwrap_or(self.tags.len());
+                self.tags.insert(idx, tag);
+            },
+        }
+        self
+    }
+
+    /// Adds a field, or replaces the value of the field with the same name,
+    /// keeping its place.
+    pub fn field<N: Into<String>, V: Into<FieldValue>>(mut self, name: N, value: V) -> Point {
+        let field = Field { name: name.into(), value: value.into() };
+        match self.fields.iter_mut().find(|f| f.name == field.name) {
+            Some(existing) => existing.value = field.value,
+            None => self.fields.push(field),
+        }
+        self
+    }
+
+    /// Sets the timestamp, stored as nanoseconds since the unix epoch.
+    ///
+    /// If `time` is out of the range of [`Timestamp`], roughly the years 1677
+    /// to 2262, the point is left without a timestamp; use
+    /// [`Point::try_timestamp`] to catch such times.
+    pub fn timestamp<T: Timestamp>(mut self, time: T) -> Point {
+        self.timestamp = time.to_unix_nanos();
+        self
+    }
+
+    /// Sets the timestamp, stored as nanoseconds since the unix epoch, failing
+    /// with [`Error::InvalidTimestamp`] if `time` is out of the range of
+    /// [`Timestamp`].
+    pub fn try_timestamp<T: Timestamp>(mut self, time: T) -> Result<Point> {
+        self.timestamp = Some(time.to_unix_nanos().ok_or(Error::InvalidTimestamp)?);
+        Ok(self)
+    }
+
+    /// Checks that the point can be written as a valid line.
+    fn validate(&self) -> Result<()> {
+        if self.measurement.is_empty() {
+            return Err(Error::EmptyMeasurement);
+        }
+        for tag in &self.tags {
+            if tag.name.is_empty() {
+                return Err(Error::InvalidKey(tag.name.clone()));
+            }
+            if tag.value.is_empty() {
+                return Err(Error::EmptyTagValue(tag.name.clone()));
+            }
+        }
+        if self.fields.is_empty() {
+            return Err(Error::NoFields);
+        }
+        if let Some(field) = self.fields.iter().find(|f| f.name.is_empty()) {
+            return Err(Error::InvalidKey(field.name.clone()));
+        }
+        Ok(())
+    }
+
+    fn build_tags<'a, I: Iterator<Item = &'a Tag>, S: Sink + ?Sized>(tags: I, s: &mut S) {
+        for tag in tags {
+            s.push(',');
+            build_escapedtagstr(&tag.name, s);
+            s.push('=');
+            build_escapedtagstr(&tag.value, s);
+        }
+    }
+}
+
+impl Metric for Point {
+    /// The point's timestamp, taken as nanoseconds.
+    fn time(&self) -> Option<i64> {
+        self.timestamp
+    }
+
+    fn measurement(&self) -> String {
+        self.measurement.clone()
+    }
+
+    fn fields(&self) -> Vec<Field> {
+        self.fields.clone()
+    }
+
+    fn tags(&self) -> Vec<Tag> {
+        self.tags.clone()
+    }
+
+    fn build_with(&self, s: &mut dyn Sink, options: &Options) -> Result<usize> {
+        self.validate()?;
+        let start = s.len();
+
+        build_escapedmeasurement(&self.measurement, s);
+        // Tags added through `Point::tag` are already sorted, but those
+        // assigned directly, or read from a line, might not be.
+        if self.tags.windows(2).all(|w| w[0].name <= w[1].name) {
+            Point::build_tags(self.tags.iter(), s);
+        } else {
+            let mut tags: Vec<&Tag> = self.tags.iter().collect();
+            tags.sort_by(|a, b| a.name.cmp(&b.name));
+            Point::build_tags(tags.into_iter(), s);
+        }
+
+        let fields_start = s.len();
+        for field in &self.fields {
+            let field_start = s.len();
+            s.push(if field_start == fields_start { ' ' } else { ',' });
+            build_escapedtagstr(&field.name, s);
+            s.push('=');
+            match field.value.build_with(s, options) {
+                Ok(true) => (),
+                Ok(false) => s.truncate(field_start),
+                Err(e) => {
+                    s.truncate(start);
+                    return Err(e);
+                },
+            }
+        }
+        if s.len() == fields_start {
+            // Every field was omitted, so there is no line to write.
+            s.truncate(start);
+            return Ok(0);
+        }
+
+        if let Some(ts) = self.timestamp {
+            s.push(' ');
+            let ts = match options.precision {
+                Some(precision) => precision.nanos_to_timestamp(ts),
+                None => ts,
+            };
+            build_integer(ts, s);
+        }
+        Ok(s.len() - start)
+    }
+
+    fn build_with_tags(&self, s: &mut dyn Sink, options: &Options, defaults: &[Tag]) -> Result<usize> {
+        if defaults.is_empty() {
+            return self.build_with(s, options);
+        }
+        let mut point = self.clone();
+        for tag in defaults {
+            if !point.tags.iter().any(|t| t.name == tag.name) {
+                point.tags.push(tag.clone());
+            }
+        }
+        point.build_with(s, options)
+    }
+}
+
+impl From<Line<'_>> for Point {
+    fn from(line: Line<'_>) -> Self {
+        Point {
+            measurement: line.measurement().to_string(),
+            tags: line.tags().map(|(name, value)| Tag {
+                name: name.into_owned(),
+                value: value.into_owned(),
+            }).collect(),
+            fields: line.fields().map(|(name, value)| Field {
+                name: name.into_owned(),
+                value: FieldValue::from(value),
+            }).collect(),
+            timestamp: line.timestamp(),
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Serialization of metrics with a set of default tags.
+
+use std::fmt;
+
+use crate::{build_escapedtagstr, Error, Metric, Options, Result, Sink, Tag};
+
+/// Writes metrics with default tags, such as the host or agent version,
+/// merged into every line.
+///
+/// Default tags are sorted along with each metric's own tags. A metric's own
+/// tag takes precedence over a default tag with the same key, unless it is
+/// omitted (e.g. an optional tag that is `None`), in which case the default
+/// is written.
+///
+/// ```
+/// use segment::{Point, Serializer};
+///
+/// let serializer = Serializer::new().tag("host", "a").tag("dc", "eu");
+/// let point = Point::new("cpu").tag("host", "b").field("value", 1i64);
+///
+/// assert_eq!(serializer.to_lineproto(&point).unwrap(), "cpu,dc=eu,host=b value=1i");
+/// ```
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Serializer {
+    options: Options,
+    // Sorted by name, with unique names
+    tags: Vec<Tag>,
+}
+
+impl Serializer {
+    /// Creates a serializer with the default [`Options`] and no default tags.
+    pub fn new() -> Serializer {
+        Serializer::default()
+    }
+
+    /// Sets the options metrics are written with.
+    pub fn options(mut self, options: Options) -> Serializer {
+        self.options = options;
+        self
+    }
+
+    /// Adds a default tag, replacing any with the same name.
+    pub fn tag<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Serializer {
+        let tag = Tag { name: name.into(), value: value.into() };
+        match self.tags.binary_search_by(|t| t.name.cmp(&tag.name)) {
+            Ok(idx) => self.tags[idx] = tag,
+            Err(idx) => self.tags.insert(idx, tag),
+        }
+        self
+    }
+
+    /// The default tags, sorted by name.
+    pub fn default_tags(&self) -> &[Tag] {
+        &self.tags
+    }
+
+    /// Appends `metric`, as a line of line protocol, to `buffer`.
+    ///
+    /// Returns the number of bytes written, as [`Metric::build_with`] does.
+    pub fn build<M: Metric + ?Sized>(&self, metric: &M, buffer: &mut dyn Sink) -> Result<usize> {
+        for tag in &self.tags {
+            if tag.name.is_empty() {
+                return Err(Error::InvalidKey(tag.name.clone()));
+            }
+            if tag.value.is_empty() {
+                return Err(Error::EmptyTagValue(tag.name.clone()));
+            }
+        }
+        metric.build_with_tags(buffer, &self.options, &self.tags)
+    }
+
+    /// Writes `metric`, as a line of line protocol, to a `fmt::Write`, such
+    /// as a `fmt::Formatter`. The line is built before anything is written.
+    ///
+    /// Returns the number of bytes written, as [`Metric::build_with`] does.
+    pub fn write_to_fmt<M: Metric + ?Sized, W: fmt::Write + ?Sized>(&self, metric: &M, writer: &mut W) -> Result<usize> {
+        let mut line = String::with_capacity(64);
+        let written = self.build(metric, &mut line)?;
+        writer.write_str(&line)?;
+        Ok(written)
+    }
+
+    /// Serializes `metric` into a newly allocated line.
+    pub fn to_lineproto<M: Metric + ?Sized>(&self, metric: &M) -> Result<String> {
+        let mut s = String::with_capacity(64);
+        self.build(metric, &mut s)?;
+        Ok(s)
+    }
+}
+
+/// Merges default tags, sorted by name, into the tags of a derived metric as
+/// they are written in order.
+#[doc(hidden)]
+pub struct DefaultTags<'a> {
+    tags: &'a [Tag],
+}
+
+impl<'a> DefaultTags<'a> {
+    pub fn new(tags: &'a [Tag]) -> DefaultTags<'a> {
+        DefaultTags { tags }
+    }
+
+    /// Writes the default tags sorting before `name`.
+    pub fn before<S: Sink + ?Sized>(&mut self, name: &str, buff: &mut S) {
+        while let Some((tag, rest)) = self.tags.split_first() {
+            if tag.name.as_str() >= name {
+                break;
+            }
+            write_tag(tag, buff);
+            self.tags = rest;
+        }
+    }
+
+    /// Passes over the default tag `name`, if any, writing it unless the
+    /// metric's own tag was `written`.
+    pub fn at<S: Sink + ?Sized>(&mut self, name: &str, written: bool, buff: &mut S) {
+        if let Some((tag, rest)) = self.tags.split_first() {
+            if tag.name == name {
+                if !written {
+                    write_tag(tag, buff);
+                }
+                self.tags = rest;
+            }
+        }
+    }
+
+    /// Writes the remaining default tags.
+    pub fn rest<S: Sink + ?Sized>(&mut self, buff: &mut S) {
+        for tag in self.tags {
+            write_tag(tag, buff);
+        }
+        self.tags = &[];
+    }
+}
+
+fn write_tag<S: Sink + ?Sized>(tag: &Tag, buff: &mut S) {
+    buff.push(',');
+    build_escapedtagstr(&tag.name, buff);
+    buff.push('=');
+    build_escapedtagstr(&tag.value, buff);
+}
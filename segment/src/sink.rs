@@ -0,0 +1,164 @@
+//! Destinations metrics are written to.
+
+use std::io;
+
+use crate::{Metric, Result, Serializer};
+
+/// A growable buffer lines of line protocol are written to.
+///
+/// Lines are written piece by piece, and the part of a line written for an
+/// omitted value, or a whole line that fails to be written, is rolled back
+/// with [`truncate`](Sink::truncate), so the buffer must be able to discard
+/// what was last written to it. Streams, such as files and sockets, are
+/// written to through a [`LineWriter`], which buffers each line.
+///
+/// Implemented for `String` and `Vec<u8>`, and for `bytes::BytesMut` with
+/// the `bytes` feature.
+pub trait Sink {
+    /// The number of bytes in the buffer.
+    fn len(&self) -> usize;
+
+    /// Whether the buffer is empty.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Shortens the buffer to its first `len` bytes, which is always a length
+    /// the buffer had before.
+    fn truncate(&mut self, len: usize);
+
+    /// Appends a string to the buffer.
+    fn push_str(&mut self, s: &str);
+
+    /// Appends a character to the buffer.
+    fn push(&mut self, c: char) {
+        self.push_str(c.encode_utf8(&mut [0; 4]));
+    }
+}
+
+impl Sink for String {
+    fn len(&self) -> usize {
+        String::len(self)
+    }
+
+    fn truncate(&mut self, len: usize) {
+        String::truncate(self, len)
+    }
+
+    fn push_str(&mut self, s: &str) {
+        String::push_str(self, s)
+    }
+
+    fn push(&mut self, c: char) {
+        String::push(self, c)
+    }
+}
+
+impl Sink for Vec<u8> {
+    fn len(&self) -> usize {
+        Vec::len(self)
+    }
+
+    fn truncate(&mut self, len: usize) {
+        Vec::truncate(self, len)
+    }
+
+    fn push_str(&mut self, s: &str) {
+        self.extend_from_slice(s.as_bytes())
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl Sink for bytes::BytesMut {
+    fn len(&self) -> usize {
+        bytes::BytesMut::len(self)
+    }
+
+    fn truncate(&mut self, len: usize) {
+        bytes::BytesMut::truncate(self, len)
+    }
+
+    fn push_str(&mut self, s: &str) {
+        self.extend_from_slice(s.as_bytes())
+    }
+}
+
+/// Writes metrics to a stream, such as a `BufWriter<File>` or a socket, each
+/// on its own line.
+///
+/// Every line is written to a buffer, reused from one line to the next, and
+/// then to the stream as a whole, so that a metric failing to serialize
+/// writes nothing.
+///
+/// ```
+/// use segment::{LineWriter, Point};
+///
+/// let mut writer = LineWriter::new(Vec::new());
+/// writer.write(&Point::new("cpu").field("value", 1i64)).unwrap();
+/// writer.write(&Point::new("mem").field("used", 2i64)).unwrap();
+///
+/// assert_eq!(writer.into_inner(), b"cpu value=1i\nmem used=2i\n");
+/// ```
+#[derive(Debug)]
+pub struct LineWriter<W: io::Write> {
+    writer: W,
+    serializer: Serializer,
+    line: Vec<u8>,
+}
+
+impl<W: io::Write> LineWriter<W> {
+    /// Creates a writer for `writer`, writing metrics with the default
+    /// [`Options`](crate::Options) and no default tags.
+    pub fn new(writer: W) -> LineWriter<W> {
+        LineWriter::with_serializer(writer, Serializer::new())
+    }
+
+    /// Creates a writer for `writer`, writing metrics through `serializer`.
+    pub fn with_serializer(writer: W, serializer: Serializer) -> LineWriter<W> {
+        LineWriter {
+            writer,
+            serializer,
+            line: Vec::with_capacity(256),
+        }
+    }
+
+    /// Writes `metric`, followed by a newline.
+    ///
+    /// Returns the number of bytes written, which is zero if every field was
+    /// omitted and so no line was written.
+    pub fn write<M: Metric + ?Sized>(&mut self, metric: &M) -> Result<usize> {
+        self.line.clear();
+        if self.serializer.build(metric, &mut self.line)? == 0 {
+            return Ok(0);
+        }
+        self.line.push(b'\n');
+        self.writer.write_all(&self.line)?;
+        Ok(self.line.len())
+    }
+
+    /// Flushes the underlying stream.
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// The serializer metrics are written through.
+    pub fn serializer(&self) -> &Serializer {
+        &self.serializer
+    }
+
+    /// A reference to the underlying stream.
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// A mutable reference to the underlying stream.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// Returns the underlying stream, which is not flushed.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
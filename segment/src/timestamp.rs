@@ -0,0 +1,95 @@
+//! Conversions between timestamp types and line protocol timestamps.
+
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A point in time that can be written as, and read back from, a line
+/// protocol timestamp.
+///
+/// Timestamps are exchanged as nanoseconds since the unix epoch, negative
+/// for times before it, which covers roughly the years 1677 to 2262. The
+/// `Metric` derive accepts any `Timestamp` type, or an `Option` of one, as
+/// the time member.
+///
+/// Implemented for `Duration` (since the unix epoch), `SystemTime`, `i64`
+/// and `u64` nanoseconds, and, with the `chrono` and `time` features,
+/// `chrono::DateTime<Utc>` and `time::OffsetDateTime`.
+pub trait Timestamp: Sized {
+    /// Nanoseconds since the unix epoch, or `None` if `self` is out of range.
+    fn to_unix_nanos(&self) -> Option<i64>;
+
+    /// Builds `Self` from nanoseconds since the unix epoch, or returns
+    /// `None` if `Self` cannot represent it.
+    fn from_unix_nanos(nanos: i64) -> Option<Self>;
+}
+
+impl Timestamp for i64 {
+    fn to_unix_nanos(&self) -> Option<i64> {
+        Some(*self)
+    }
+
+    fn from_unix_nanos(nanos: i64) -> Option<Self> {
+        Some(nanos)
+    }
+}
+
+impl Timestamp for u64 {
+    fn to_unix_nanos(&self) -> Option<i64> {
+        i64::try_from(*self).ok()
+    }
+
+    fn from_unix_nanos(nanos: i64) -> Option<Self> {
+        u64::try_from(nanos).ok()
+    }
+}
+
+/// A duration since the unix epoch, which cannot be before it.
+impl Timestamp for Duration {
+    fn to_unix_nanos(&self) -> Option<i64> {
+        i64::try_from(self.as_nanos()).ok()
+    }
+
+    fn from_unix_nanos(nanos: i64) -> Option<Self> {
+        u64::try_from(nanos).ok().map(Duration::from_nanos)
+    }
+}
+
+impl Timestamp for SystemTime {
+    fn to_unix_nanos(&self) -> Option<i64> {
+        match self.duration_since(UNIX_EPOCH) {
+            Ok(after) => after.to_unix_nanos(),
+            Err(e) => i64::try_from(e.duration().as_nanos()).ok().map(|before| -before),
+        }
+    }
+
+    fn from_unix_nanos(nanos: i64) -> Option<Self> {
+        let offset = Duration::from_nanos(nanos.unsigned_abs());
+        if nanos < 0 {
+            UNIX_EPOCH.checked_sub(offset)
+        } else {
+            UNIX_EPOCH.checked_add(offset)
+        }
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl Timestamp for chrono::DateTime<chrono::Utc> {
+    fn to_unix_nanos(&self) -> Option<i64> {
+        self.timestamp_nanos_opt()
+    }
+
+    fn from_unix_nanos(nanos: i64) -> Option<Self> {
+        Some(chrono::DateTime::from_timestamp_nanos(nanos))
+    }
+}
+
+#[cfg(feature = "time")]
+impl Timestamp for time::OffsetDateTime {
+    fn to_unix_nanos(&self) -> Option<i64> {
+        i64::try_from(self.unix_timestamp_nanos()).ok()
+    }
+
+    fn from_unix_nanos(nanos: i64) -> Option<Self> {
+        time::OffsetDateTime::from_unix_timestamp_nanos(i128::from(nanos)).ok()
+    }
+}
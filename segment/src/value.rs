@@ -0,0 +1,424 @@
+//! Conversion of Rust values into tag and field values.
+//!
+//! `#[derive(Metric)]` writes every tag and field through [`ToTagValue`] and
+//! [`ToFieldValue`], so any type implementing them, including newtypes
+//! defined outside this crate, can be used as a member. C-like enums can
+//! derive both traits with `#[derive(TagValue)]`, and any other type
+//! implementing `Display` can be written through [`Displayed`].
+
+use std::borrow::Cow;
+use std::fmt::{self, Write};
+use std::num::{
+    NonZeroI16, NonZeroI32, NonZeroI64, NonZeroI8, NonZeroIsize,
+    NonZeroU16, NonZeroU32, NonZeroU64, NonZeroU8, NonZeroUsize, Wrapping,
+};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::{build_escapedfieldstr, build_escapedtagstr, build_float, build_integer, build_unsigned, Error, FieldValue, Options, Result, Sink};
+
+/// A value that can be written as a field value.
+///
+/// Only [`to_field_value`](ToFieldValue::to_field_value) must be
+/// implemented; [`write_field`](ToFieldValue::write_field) can be overridden
+/// to write the value without going through a [`FieldValue`].
+///
+/// ```
+/// use segment::{FieldValue, ToFieldValue};
+///
+/// struct Bytes(u64);
+///
+/// impl ToFieldValue for Bytes {
+///     fn to_field_value(&self) -> Option<FieldValue> {
+///         self.0.to_field_value()
+///     }
+/// }
+/// ```
+pub trait ToFieldValue {
+    /// Converts the value, or returns `None` if the field is to be omitted.
+    fn to_field_value(&self) -> Option<FieldValue>;
+
+    /// Writes the value, with its type suffix, to the end of `buff`.
+    ///
+    /// Returns `false`, having written nothing, if the field is to be
+    /// omitted, either because it has no value or because of `options`.
+    fn write_field<S: Sink + ?Sized>(&self, buff: &mut S, options: &Options) -> Result<bool> {
+        match self.to_field_value() {
+            Some(value) => value.build_with(buff, options),
+            None => Ok(false),
+        }
+    }
+}
+
+/// A value that can be written as a tag value.
+///
+/// Only [`to_tag_value`](ToTagValue::to_tag_value) must be implemented;
+/// [`write_tag`](ToTagValue::write_tag) can be overridden to write the value
+/// without an intermediate string.
+pub trait ToTagValue {
+    /// Converts the value, unescaped, or returns `None` if the tag is to be
+    /// omitted.
+    fn to_tag_value(&self) -> Option<Cow<'_, str>>;
+
+    /// Writes the escaped value to the end of `buff`.
+    ///
+    /// Returns `false`, having written nothing, if the tag is to be omitted.
+    fn write_tag<S: Sink + ?Sized>(&self, buff: &mut S) -> Result<bool> {
+        match self.to_tag_value() {
+            Some(value) => {
+                build_escapedtagstr(&value, buff);
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+}
+
+impl ToFieldValue for FieldValue {
+    fn to_field_value(&self) -> Option<FieldValue> {
+        Some(self.clone())
+    }
+
+    fn write_field<S: Sink + ?Sized>(&self, buff: &mut S, options: &Options) -> Result<bool> {
+        self.build_with(buff, options)
+    }
+}
+
+impl ToFieldValue for bool {
+    fn to_field_value(&self) -> Option<FieldValue> {
+        Some(FieldValue::Bool(*self))
+    }
+
+    fn write_field<S: Sink + ?Sized>(&self, buff: &mut S, _: &Options) -> Result<bool> {
+        buff.push(if *self { 't' } else { 'f' });
+        Ok(true)
+    }
+}
+
+impl ToTagValue for bool {
+    fn to_tag_value(&self) -> Option<Cow<'_, str>> {
+        Some(Cow::Borrowed(if *self { "true" } else { "false" }))
+    }
+}
+
+macro_rules! to_value_signed {
+    ( $( $t:ty => $variant:ident ),* ) => {
+        $(
+            impl ToFieldValue for $t {
+                fn to_field_value(&self) -> Option<FieldValue> {
+                    Some(FieldValue::$variant((*self).into()))
+                }
+
+                fn write_field<S: Sink + ?Sized>(&self, buff: &mut S, _: &Options) -> Result<bool> {
+                    build_integer(*self, buff);
+                    buff.push('i');
+                    Ok(true)
+                }
+            }
+        )*
+    };
+}
+
+to_value_signed!(i8 => Int32, i16 => Int32, i32 => Int32, i64 => Int64);
+
+impl ToFieldValue for isize {
+    fn to_field_value(&self) -> Option<FieldValue> {
+        Some(FieldValue::Int64(*self as i64))
+    }
+
+    fn write_field<S: Sink + ?Sized>(&self, buff: &mut S, options: &Options) -> Result<bool> {
+        (*self as i64).write_field(buff, options)
+    }
+}
+
+macro_rules! to_value_unsigned {
+    ( $( $t:ty => $variant:ident ),* ) => {
+        $(
+            impl ToFieldValue for $t {
+                fn to_field_value(&self) -> Option<FieldValue> {
+                    Some(FieldValue::$variant((*self).into()))
+                }
+
+                fn write_field<S: Sink + ?Sized>(&self, buff: &mut S, options: &Options) -> Result<bool> {
+                    build_unsigned(u64::from(*self), buff, options.unsigned)?;
+                    Ok(true)
+                }
+            }
+        )*
+    };
+}
+
+to_value_unsigned!(u8 => UInt32, u16 => UInt32, u32 => UInt32, u64 => UInt64);
+
+impl ToFieldValue for usize {
+    fn to_field_value(&self) -> Option<FieldValue> {
+        Some(FieldValue::UInt64(*self as u64))
+    }
+
+    fn write_field<S: Sink + ?Sized>(&self, buff: &mut S, options: &Options) -> Result<bool> {
+        (*self as u64).write_field(buff, options)
+    }
+}
+
+macro_rules! to_tag_value_int {
+    ( $( $t:ty ),* ) => {
+        $(
+            impl ToTagValue for $t {
+                fn to_tag_value(&self) -> Option<Cow<'_, str>> {
+                    Some(Cow::Owned(self.to_string()))
+                }
+
+                fn write_tag<S: Sink + ?Sized>(&self, buff: &mut S) -> Result<bool> {
+                    build_integer(*self, buff);
+                    Ok(true)
+                }
+            }
+        )*
+    };
+}
+
+to_tag_value_int!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl ToFieldValue for f32 {
+    fn to_field_value(&self) -> Option<FieldValue> {
+        Some(FieldValue::Float32(*self))
+    }
+}
+
+impl ToFieldValue for f64 {
+    fn to_field_value(&self) -> Option<FieldValue> {
+        Some(FieldValue::Float64(*self))
+    }
+}
+
+macro_rules! to_tag_value_float {
+    ( $( $t:ty ),* ) => {
+        $(
+            /// NaN and infinite values have no line protocol representation,
+            /// so they are omitted by `to_tag_value` and fail to be written.
+            impl ToTagValue for $t {
+                fn to_tag_value(&self) -> Option<Cow<'_, str>> {
+                    if !self.is_finite() {
+                        return None;
+                    }
+                    let mut value = String::new();
+                    build_float(*self, &mut value).ok()?;
+                    Some(Cow::Owned(value))
+                }
+
+                fn write_tag<S: Sink + ?Sized>(&self, buff: &mut S) -> Result<bool> {
+                    if !self.is_finite() {
+                        return Err(Error::NonFiniteFloat);
+                    }
+                    build_float(*self, buff)?;
+                    Ok(true)
+                }
+            }
+        )*
+    };
+}
+
+to_tag_value_float!(f32, f64);
+
+impl ToFieldValue for str {
+    fn to_field_value(&self) -> Option<FieldValue> {
+        Some(FieldValue::Str(self.to_string()))
+    }
+
+    fn write_field<S: Sink + ?Sized>(&self, buff: &mut S, _: &Options) -> Result<bool> {
+        build_escapedfieldstr(self, buff);
+        Ok(true)
+    }
+}
+
+impl ToTagValue for str {
+    fn to_tag_value(&self) -> Option<Cow<'_, str>> {
+        Some(Cow::Borrowed(self))
+    }
+}
+
+impl ToFieldValue for String {
+    fn to_field_value(&self) -> Option<FieldValue> {
+        Some(FieldValue::Str(self.clone()))
+    }
+
+    fn write_field<S: Sink + ?Sized>(&self, buff: &mut S, options: &Options) -> Result<bool> {
+        self.as_str().write_field(buff, options)
+    }
+}
+
+impl ToTagValue for String {
+    fn to_tag_value(&self) -> Option<Cow<'_, str>> {
+        Some(Cow::Borrowed(self))
+    }
+}
+
+/// Absent values are omitted from the line.
+impl<T: ToFieldValue> ToFieldValue for Option<T> {
+    fn to_field_value(&self) -> Option<FieldValue> {
+        self.as_ref()?.to_field_value()
+    }
+
+    fn write_field<S: Sink + ?Sized>(&self, buff: &mut S, options: &Options) -> Result<bool> {
+        match self {
+            Some(value) => value.write_field(buff, options),
+            None => Ok(false),
+        }
+    }
+}
+
+/// Absent values are omitted from the line.
+impl<T: ToTagValue> ToTagValue for Option<T> {
+    fn to_tag_value(&self) -> Option<Cow<'_, str>> {
+        self.as_ref()?.to_tag_value()
+    }
+
+    fn write_tag<S: Sink + ?Sized>(&self, buff: &mut S) -> Result<bool> {
+        match self {
+            Some(value) => value.write_tag(buff),
+            None => Ok(false),
+        }
+    }
+}
+
+impl<'a, T: ToFieldValue + ToOwned + ?Sized> ToFieldValue for Cow<'a, T> {
+    fn to_field_value(&self) -> Option<FieldValue> {
+        (**self).to_field_value()
+    }
+
+    fn write_field<S: Sink + ?Sized>(&self, buff: &mut S, options: &Options) -> Result<bool> {
+        (**self).write_field(buff, options)
+    }
+}
+
+impl<'a, T: ToTagValue + ToOwned + ?Sized> ToTagValue for Cow<'a, T> {
+    fn to_tag_value(&self) -> Option<Cow<'_, str>> {
+        (**self).to_tag_value()
+    }
+
+    fn write_tag<S: Sink + ?Sized>(&self, buff: &mut S) -> Result<bool> {
+        (**self).write_tag(buff)
+    }
+}
+
+// References and smart pointers write the value they point to.
+macro_rules! to_value_deref {
+    ( $( $t:ty ),* ) => {
+        $(
+            impl<T: ToFieldValue + ?Sized> ToFieldValue for $t {
+                fn to_field_value(&self) -> Option<FieldValue> {
+                    (**self).to_field_value()
+                }
+
+                fn write_field<S: Sink + ?Sized>(&self, buff: &mut S, options: &Options) -> Result<bool> {
+                    (**self).write_field(buff, options)
+                }
+            }
+
+            impl<T: ToTagValue + ?Sized> ToTagValue for $t {
+                fn to_tag_value(&self) -> Option<Cow<'_, str>> {
+                    (**self).to_tag_value()
+                }
+
+                fn write_tag<S: Sink + ?Sized>(&self, buff: &mut S) -> Result<bool> {
+                    (**self).write_tag(buff)
+                }
+            }
+        )*
+    };
+}
+
+to_value_deref!(&T, Box<T>, Rc<T>, Arc<T>);
+
+// Wrappers write the integer they wrap.
+macro_rules! to_value_wrapper {
+    ( $( $t:ty => $get:ident ),* ) => {
+        $(
+            impl ToFieldValue for $t {
+                fn to_field_value(&self) -> Option<FieldValue> {
+                    self.$get().to_field_value()
+                }
+
+                fn write_field<S: Sink + ?Sized>(&self, buff: &mut S, options: &Options) -> Result<bool> {
+                    self.$get().write_field(buff, options)
+                }
+            }
+
+            impl ToTagValue for $t {
+                fn to_tag_value(&self) -> Option<Cow<'_, str>> {
+                    Some(Cow::Owned(self.$get().to_string()))
+                }
+
+                fn write_tag<S: Sink + ?Sized>(&self, buff: &mut S) -> Result<bool> {
+                    self.$get().write_tag(buff)
+                }
+            }
+        )*
+    };
+}
+
+to_value_wrapper!(
+    NonZeroI8 => get, NonZeroI16 => get, NonZeroI32 => get, NonZeroI64 => get, NonZeroIsize => get,
+    NonZeroU8 => get, NonZeroU16 => get, NonZeroU32 => get, NonZeroU64 => get, NonZeroUsize => get
+);
+
+impl<T: ToFieldValue> ToFieldValue for Wrapping<T> {
+    fn to_field_value(&self) -> Option<FieldValue> {
+        self.0.to_field_value()
+    }
+
+    fn write_field<S: Sink + ?Sized>(&self, buff: &mut S, options: &Options) -> Result<bool> {
+        self.0.write_field(buff, options)
+    }
+}
+
+impl<T: ToTagValue> ToTagValue for Wrapping<T> {
+    fn to_tag_value(&self) -> Option<Cow<'_, str>> {
+        self.0.to_tag_value()
+    }
+
+    fn write_tag<S: Sink + ?Sized>(&self, buff: &mut S) -> Result<bool> {
+        self.0.write_tag(buff)
+    }
+}
+
+/// Writes a value through its `Display` implementation: as a tag value, or as
+/// a string field.
+///
+/// Members marked `#[segment(display)]` are written through this wrapper, and
+/// decoded back through `FromStr`.
+///
+/// ```
+/// use segment::{Displayed, ToTagValue};
+///
+/// let tag = Displayed(std::net::Ipv4Addr::LOCALHOST);
+/// assert_eq!(tag.to_tag_value().unwrap(), "127.0.0.1");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct Displayed<T>(pub T);
+
+impl<T: fmt::Display> ToTagValue for Displayed<T> {
+    fn to_tag_value(&self) -> Option<Cow<'_, str>> {
+        Some(Cow::Owned(self.0.to_string()))
+    }
+
+    fn write_tag<S: Sink + ?Sized>(&self, buff: &mut S) -> Result<bool> {
+        let mut value = String::new();
+        write!(value, "{}", self.0)?;
+        build_escapedtagstr(&value, buff);
+        Ok(true)
+    }
+}
+
+impl<T: fmt::Display> ToFieldValue for Displayed<T> {
+    fn to_field_value(&self) -> Option<FieldValue> {
+        Some(FieldValue::Str(self.0.to_string()))
+    }
+
+    fn write_field<S: Sink + ?Sized>(&self, buff: &mut S, _: &Options) -> Result<bool> {
+        let mut value = String::new();
+        write!(value, "{}", self.0)?;
+        build_escapedfieldstr(&value, buff);
+        Ok(true)
+    }
+}
@@ -0,0 +1,80 @@
+//! The derives are used with the whole crate glob-imported, which brings
+//! `segment::Result` into scope in place of the prelude's `Result`.
+
+use std::collections::BTreeMap;
+
+use segment::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, TagValue)]
+enum Region {
+    Eu,
+    #[segment(rename = "us-east")]
+    UsEast,
+}
+
+#[derive(Debug, PartialEq, Metric, FromLineProto)]
+#[segment(measurement="cpu", tag(env = "prod"))]
+struct Cpu {
+    #[segment(tag)]
+    host: String,
+    #[segment(tag)]
+    region: Option<Region>,
+    #[segment(field)]
+    value: f64,
+    #[segment(field, default)]
+    count: i64,
+    #[segment(time)]
+    timestamp: Option<i64>,
+    other: Vec<u8>,
+}
+
+#[derive(Flatten)]
+struct Origin {
+    #[segment(tag)]
+    dc: String,
+    #[segment(field)]
+    hops: u32,
+}
+
+fn usage_measurement(usage: &Usage) -> String {
+    format!("{}_usage", usage.tenant)
+}
+
+#[derive(Metric)]
+#[segment(measurement_fn = "usage_measurement")]
+struct Usage {
+    tenant: String,
+    #[segment(flatten)]
+    origin: Origin,
+    #[segment(tags)]
+    labels: BTreeMap<String, String>,
+    #[segment(field)]
+    bytes: Option<u64>,
+}
+
+#[test]
+fn derives_with_glob_import() {
+    let metric = Cpu {
+        host: "a".to_string(),
+        region: Some(Region::UsEast),
+        value: 0.5,
+        count: 2,
+        timestamp: Some(7),
+        other: Vec::new(),
+    };
+    let line = metric.to_lineproto().unwrap();
+    assert_eq!(line, "cpu,env=prod,host=a,region=us-east value=0.5,count=2i 7");
+    assert_eq!(Cpu::from_lineproto(&line).unwrap(), metric);
+
+    let decoded = Cpu::from_lineproto("cpu,host=b value=1.0").unwrap();
+    assert_eq!((decoded.region, decoded.count, decoded.timestamp), (None, 0, None));
+
+    let metric = Usage {
+        tenant: "acme".to_string(),
+        origin: Origin { dc: "eu".to_string(), hops: 3 },
+        labels: vec![("app".to_string(), "web".to_string())].into_iter().collect(),
+        bytes: Some(10),
+    };
+    assert_eq!(metric.to_lineproto().unwrap(), "acme_usage,app=web,dc=eu bytes=10i,hops=3i");
+    assert_eq!(Region::Eu.to_tag_value().unwrap(), "Eu");
+}
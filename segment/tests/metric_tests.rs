@@ -1,9 +1,9 @@
 
 use std::time::Duration;
 
-use segment::Metric;
+use segment::{DecodeError, Error, FromLineProto, Metric, NonFinite, Options, Precision, Unsigned};
 
-#[derive(Metric)]
+#[derive(Debug, PartialEq, Metric, FromLineProto)]
 #[segment(measurement="cpu")]
 struct Minimal {
     #[segment(time)]
@@ -24,7 +24,7 @@ fn minimal_test() {
     assert_eq!(s, "cpu value=42.0 0");
 }
 
-#[derive(Metric)]
+#[derive(Debug, PartialEq, Metric, FromLineProto)]
 #[segment(measurement="cpu")]
 struct MultiTag {
     #[segment(time)]
@@ -57,7 +57,7 @@ fn multiple_tags() {
 }
 
 
-#[derive(Metric)]
+#[derive(Debug, PartialEq, Metric, FromLineProto)]
 #[segment(measurement="cpu")]
 struct MultiField {
     #[segment(time)]
@@ -72,18 +72,17 @@ struct MultiField {
 fn multiple_fields() {
     let metric = MultiField {
         timestamp: Duration::from_nanos(0),
-        x: std::f32::NAN,
+        x: f32::NAN,
         y: 42,
     };
 
     let mut s = String::new();
     let _ = metric.build(&mut s);
 
-    // TODO: Don't serialize NaN and Inf floats.
     assert_eq!(s, "cpu y=42i 0");
 }
 
-#[derive(Metric)]
+#[derive(Debug, PartialEq, Metric, FromLineProto)]
 #[segment(measurement="cpu")]
 struct StringNewline {
     #[segment(time)]
@@ -105,7 +104,7 @@ fn string_newlines() {
     assert_eq!(s, "cpu value=\"x\\ny\" 0");
 }
 
-#[derive(Metric)]
+#[derive(Debug, PartialEq, Metric, FromLineProto)]
 #[segment(measurement="cpu")]
 struct TagNewline {
     #[segment(time)]
@@ -130,7 +129,7 @@ fn tag_newlines() {
     assert_eq!(s, "cpu,host=x\\ny value=42i 0");
 }
 
-#[derive(Metric)]
+#[derive(Debug, PartialEq, Metric, FromLineProto)]
 #[segment(measurement="cpu")]
 struct StringField {
     #[segment(time)]
@@ -151,3 +150,1034 @@ fn string_fields() {
 
     assert_eq!(s, "cpu value=\"howdy\" 0");
 }
+
+/// Serializes `metric`, and decodes it back again.
+fn round_trip<M: Metric + FromLineProto>(metric: &M) -> Result<M, DecodeError> {
+    let mut s = String::new();
+    let _ = metric.build(&mut s);
+    M::from_lineproto(&s)
+}
+
+#[test]
+fn round_trip_minimal() {
+    let metric = Minimal {
+        timestamp: Duration::from_nanos(1_556_813_561_098_000_000),
+        value: 42.5,
+    };
+    assert_eq!(round_trip(&metric), Ok(metric));
+}
+
+#[test]
+fn round_trip_multiple_tags() {
+    let metric = MultiTag {
+        timestamp: Duration::from_nanos(0),
+        host: "local host".to_string(),
+        cpu: "CPU.0".to_string(),
+        value: 0.1,
+    };
+    assert_eq!(round_trip(&metric), Ok(metric));
+}
+
+#[test]
+fn round_trip_multiple_fields() {
+    let metric = MultiField {
+        timestamp: Duration::from_nanos(0),
+        x: 3.25,
+        y: u32::MAX,
+    };
+    assert_eq!(round_trip(&metric), Ok(metric));
+}
+
+#[test]
+fn round_trip_newlines() {
+    let field = StringNewline {
+        timestamp: Duration::from_nanos(0),
+        value: "x\ny \"quoted\"".to_string(),
+    };
+    assert_eq!(round_trip(&field), Ok(field));
+
+    let tag = TagNewline {
+        timestamp: Duration::from_nanos(0),
+        host: "x\ny".to_string(),
+        value: 42,
+    };
+    assert_eq!(round_trip(&tag), Ok(tag));
+}
+
+#[test]
+fn decode_errors() {
+    assert_eq!(
+        Minimal::from_lineproto("mem value=1.0 0"),
+        Err(DecodeError::Measurement("mem".to_string())));
+    assert_eq!(
+        Minimal::from_lineproto("cpu other=1.0 0"),
+        Err(DecodeError::UnexpectedField("other".to_string())));
+    assert_eq!(
+        Minimal::from_lineproto("cpu,host=a value=1.0 0"),
+        Err(DecodeError::UnexpectedTag("host".to_string())));
+    assert_eq!(
+        Minimal::from_lineproto("cpu value=\"x\" 0"),
+        Err(DecodeError::InvalidField("value".to_string())));
+    assert_eq!(
+        Minimal::from_lineproto("cpu value=1.0"),
+        Err(DecodeError::MissingTime));
+    assert_eq!(
+        MultiTag::from_lineproto("cpu,host=a value=1.0 0"),
+        Err(DecodeError::MissingTag("cpu".to_string())));
+    assert_eq!(
+        MultiField::from_lineproto("cpu x=1.0,y=-1i 0"),
+        Err(DecodeError::InvalidField("y".to_string())));
+    assert_eq!(
+        StringField::from_lineproto("cpu 0"),
+        Err(DecodeError::Parse(segment::parse_line("cpu 0").unwrap_err())));
+}
+
+#[derive(Debug, PartialEq, Metric, FromLineProto)]
+#[segment(measurement="cpu load,total")]
+struct EscapedNames {
+    #[segment(time)]
+    timestamp: Duration,
+    #[segment(tag, rename="host name")]
+    host: String,
+    #[segment(field, rename="a=b,c")]
+    value: String,
+}
+
+#[test]
+fn escaped_names() {
+    let metric = EscapedNames {
+        timestamp: Duration::from_nanos(0),
+        host: "my,host=x.y".to_string(),
+        value: "say \"hi\" C:\\dir".to_string(),
+    };
+
+    let mut s = String::new();
+    let _ = metric.build(&mut s);
+
+    assert_eq!(s, r#"cpu\ load\,total,host\ name=my\,host\=x.y a\=b\,c="say \"hi\" C:\\dir" 0"#);
+    assert_eq!(round_trip(&metric), Ok(metric));
+}
+
+#[test]
+fn escape_contexts() {
+    assert_eq!(segment::escape_measurement("a b,c=d\"e"), r#"a\ b\,c=d"e"#);
+    assert_eq!(segment::escape_tagstr("a b,c=d\"e.f\\g"), r#"a\ b\,c\=d"e.f\\g"#);
+    assert_eq!(segment::escape_fieldstr("a b,c=d\"e\\f"), r#""a b,c=d\"e\\f""#);
+}
+
+#[derive(Metric)]
+#[segment(measurement="cpu")]
+struct FloatFields {
+    #[segment(time)]
+    timestamp: Duration,
+    #[segment(field)]
+    x: f64,
+    #[segment(field)]
+    y: f32,
+    #[segment(field)]
+    z: f64,
+}
+
+#[test]
+fn non_finite_policies() {
+    let metric = FloatFields {
+        timestamp: Duration::from_nanos(0),
+        x: f64::NAN,
+        y: 1.5,
+        z: f64::INFINITY,
+    };
+
+    let mut s = String::new();
+    metric.build(&mut s).unwrap();
+    assert_eq!(s, "cpu y=1.5 0");
+
+    let substitute = Options::default().non_finite(NonFinite::Substitute(-1.0));
+    let mut s = String::new();
+    metric.build_with(&mut s, &substitute).unwrap();
+    assert_eq!(s, "cpu x=-1.0,y=1.5,z=-1.0 0");
+
+    let error = Options::default().non_finite(NonFinite::Error);
+    let mut s = String::new();
+    match metric.build_with(&mut s, &error) {
+        Err(Error::NonFiniteFloat) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(s, "");
+}
+
+#[test]
+fn all_fields_skipped() {
+    let metric = FloatFields {
+        timestamp: Duration::from_nanos(0),
+        x: f64::NAN,
+        y: f32::NAN,
+        z: f64::NEG_INFINITY,
+    };
+
+    let mut s = String::from("cpu x=1.0 0\n");
+    assert_eq!(metric.build(&mut s).unwrap(), 0);
+    assert_eq!(s, "cpu x=1.0 0\n");
+    assert_eq!(metric.to_lineproto().unwrap(), "");
+
+    let metric = FloatFields { y: 2.0, ..metric };
+    assert_eq!(metric.to_lineproto().unwrap(), "cpu y=2.0 0");
+}
+
+#[derive(Metric)]
+#[segment(measurement="probe")]
+struct FloatTag {
+    #[segment(tag)]
+    gain: f64,
+    #[segment(field)]
+    value: i64,
+}
+
+#[test]
+fn non_finite_tags() {
+    use segment::ToTagValue;
+
+    let metric = FloatTag { gain: 0.5, value: 1 };
+    assert_eq!(metric.to_lineproto().unwrap(), "probe,gain=0.5 value=1i");
+
+    for gain in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY].iter() {
+        assert_eq!(gain.to_tag_value(), None);
+        assert_eq!((*gain as f32).to_tag_value(), None);
+
+        let metric = FloatTag { gain: *gain, value: 1 };
+        let mut s = String::from("kept");
+        match metric.build(&mut s) {
+            Err(Error::NonFiniteFloat) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(s, "kept");
+        assert!(metric.tags().is_empty());
+    }
+}
+
+#[derive(Debug, PartialEq, Metric, FromLineProto)]
+#[segment(measurement="health")]
+struct Flags {
+    #[segment(time)]
+    timestamp: Duration,
+    #[segment(tag)]
+    primary: bool,
+    #[segment(field)]
+    up: bool,
+    #[segment(field)]
+    degraded: bool,
+}
+
+#[test]
+fn bool_fields() {
+    let metric = Flags {
+        timestamp: Duration::from_nanos(0),
+        primary: true,
+        up: true,
+        degraded: false,
+    };
+
+    let mut s = String::new();
+    let _ = metric.build(&mut s);
+
+    assert_eq!(s, "health,primary=true up=t,degraded=f 0");
+    assert_eq!(metric.tags()[0].value, "true");
+    assert_eq!(round_trip(&metric), Ok(metric));
+}
+
+#[derive(Debug, PartialEq, Metric, FromLineProto)]
+#[segment(measurement="disk")]
+struct Counters {
+    #[segment(time)]
+    timestamp: Duration,
+    #[segment(field)]
+    used: u64,
+    #[segment(field, unsigned)]
+    total: u64,
+    #[segment(field)]
+    inodes: u32,
+}
+
+#[test]
+fn unsigned_modes() {
+    let metric = Counters {
+        timestamp: Duration::from_nanos(0),
+        used: u64::MAX,
+        total: u64::MAX,
+        inodes: 7,
+    };
+
+    let mut s = String::new();
+    match metric.build(&mut s) {
+        Err(Error::UnsignedOverflow(u)) => assert_eq!(u, u64::MAX),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(s, "");
+
+    let clamp = Options::default().unsigned(Unsigned::Clamp);
+    metric.build_with(&mut s, &clamp).unwrap();
+    assert_eq!(s, "disk used=9223372036854775807i,total=18446744073709551615u,inodes=7i 0");
+
+    let native = Options::default().unsigned(Unsigned::Native);
+    let mut s = String::new();
+    metric.build_with(&mut s, &native).unwrap();
+    assert_eq!(s, "disk used=18446744073709551615u,total=18446744073709551615u,inodes=7u 0");
+    assert_eq!(Counters::from_lineproto(&s), Ok(metric));
+}
+
+#[derive(Debug, PartialEq, Metric, FromLineProto)]
+#[segment(measurement="cpu")]
+struct Coarse {
+    #[segment(time, precision = "ms")]
+    timestamp: Duration,
+    #[segment(field)]
+    value: i64,
+}
+
+#[test]
+fn timestamp_precision() {
+    let metric = Coarse {
+        timestamp: Duration::new(1_556_813_561, 98_999_999),
+        value: 1,
+    };
+    // Timestamps are truncated, never rounded up.
+    assert_eq!(metric.to_lineproto().unwrap(), "cpu value=1i 1556813561098");
+
+    let cases = vec![
+        (Precision::Nanoseconds, "1556813561098999999"),
+        (Precision::Microseconds, "1556813561098999"),
+        (Precision::Milliseconds, "1556813561098"),
+        (Precision::Seconds, "1556813561"),
+    ];
+    for (precision, ts) in cases {
+        let mut s = String::new();
+        metric.build_with(&mut s, &Options::default().precision(precision)).unwrap();
+        assert_eq!(s, format!("cpu value=1i {}", ts), "precision {}", precision.as_str());
+    }
+
+    assert_eq!(Coarse::from_lineproto("cpu value=1i 1556813561098"), Ok(Coarse {
+        timestamp: Duration::from_millis(1_556_813_561_098),
+        value: 1,
+    }));
+    assert_eq!(
+        Coarse::from_lineproto("cpu value=1i -1"),
+        Err(DecodeError::InvalidTime(-1)),
+    );
+}
+
+#[derive(Debug, PartialEq, Metric, FromLineProto)]
+#[segment(measurement="heartbeat")]
+struct Heartbeat {
+    #[segment(tag)]
+    host: String,
+    #[segment(field)]
+    alive: bool,
+}
+
+#[derive(Debug, PartialEq, Metric, FromLineProto)]
+#[segment(measurement="heartbeat")]
+struct MaybeTimed {
+    #[segment(time)]
+    timestamp: Option<Duration>,
+    #[segment(field)]
+    alive: bool,
+}
+
+#[test]
+fn optional_timestamps() {
+    let metric = Heartbeat { host: "a".to_string(), alive: true };
+    assert_eq!(metric.time(), None);
+    assert_eq!(metric.to_lineproto().unwrap(), "heartbeat,host=a alive=t");
+    assert_eq!(round_trip(&metric), Ok(metric));
+    // Timestamps assigned by the server are ignored.
+    assert_eq!(
+        Heartbeat::from_lineproto("heartbeat,host=a alive=t 10"),
+        Ok(Heartbeat { host: "a".to_string(), alive: true }));
+
+    let untimed = MaybeTimed { timestamp: None, alive: false };
+    assert_eq!(untimed.to_lineproto().unwrap(), "heartbeat alive=f");
+    assert_eq!(round_trip(&untimed), Ok(untimed));
+
+    let timed = MaybeTimed { timestamp: Some(Duration::from_nanos(10)), alive: true };
+    assert_eq!(timed.time(), Some(10));
+    assert_eq!(timed.to_lineproto().unwrap(), "heartbeat alive=t 10");
+    assert_eq!(round_trip(&timed), Ok(timed));
+}
+
+#[derive(Debug, PartialEq, Metric, FromLineProto)]
+#[segment(measurement="sensor")]
+struct Sparse {
+    #[segment(time)]
+    timestamp: Duration,
+    #[segment(tag)]
+    room: Option<String>,
+    #[segment(tag)]
+    id: u32,
+    #[segment(field)]
+    temp: Option<f64>,
+    #[segment(field)]
+    label: Option<String>,
+    #[segment(field)]
+    count: Option<u32>,
+    #[segment(field)]
+    ok: Option<bool>,
+}
+
+#[test]
+fn optional_members() {
+    let metric = Sparse {
+        timestamp: Duration::from_nanos(0),
+        room: Some("kitchen".to_string()),
+        id: 3,
+        temp: Some(21.5),
+        label: Some("a b".to_string()),
+        count: Some(2),
+        ok: Some(true),
+    };
+    assert_eq!(metric.to_lineproto().unwrap(), "sensor,id=3,room=kitchen temp=21.5,label=\"a b\",count=2i,ok=t 0");
+    assert_eq!(metric.tags().len(), 2);
+    assert_eq!(round_trip(&metric), Ok(metric));
+
+    let metric = Sparse {
+        timestamp: Duration::from_nanos(0),
+        room: None,
+        id: 3,
+        temp: None,
+        label: Some("x".to_string()),
+        count: None,
+        ok: Some(false),
+    };
+    assert_eq!(metric.to_lineproto().unwrap(), "sensor,id=3 label=\"x\",ok=f 0");
+    assert_eq!(metric.tags().len(), 1);
+    assert_eq!(metric.fields().len(), 2);
+    assert_eq!(round_trip(&metric), Ok(metric));
+
+    let mut metric = Sparse {
+        timestamp: Duration::from_nanos(0),
+        room: None,
+        id: 3,
+        temp: Some(f64::NAN),
+        label: None,
+        count: None,
+        ok: None,
+    };
+    let mut s = String::new();
+    assert_eq!(metric.build(&mut s).unwrap(), 0);
+    assert_eq!(s, "");
+
+    metric.temp = None;
+    match metric.build(&mut s) {
+        Err(Error::NoFields) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(s, "");
+}
+
+#[derive(Metric)]
+#[segment(measurement="request")]
+struct Borrowed<'a, T: Copy> where T: PartialEq + std::fmt::Debug {
+    #[segment(time)]
+    timestamp: Duration,
+    #[segment(tag)]
+    host: &'a str,
+    #[segment(tag)]
+    path: Option<&'a str>,
+    #[segment(field)]
+    method: &'a str,
+    #[segment(field)]
+    bytes: u64,
+    limit: T,
+}
+
+#[test]
+fn borrowed_members() {
+    let host = String::from("my host");
+    let metric = Borrowed {
+        timestamp: Duration::from_nanos(0),
+        host: &host,
+        path: Some("/index"),
+        method: "GET",
+        bytes: 10,
+        limit: 5u32,
+    };
+    assert_eq!(metric.limit, 5);
+    assert_eq!(metric.to_lineproto().unwrap(), "request,host=my\\ host,path=/index method=\"GET\",bytes=10i 0");
+}
+
+#[derive(Debug, PartialEq, Metric, FromLineProto)]
+#[segment(measurement="log")]
+struct SharedStrings {
+    #[segment(time)]
+    timestamp: Duration,
+    #[segment(tag)]
+    service: std::sync::Arc<str>,
+    #[segment(tag)]
+    level: Option<Box<str>>,
+    #[segment(field)]
+    message: std::borrow::Cow<'static, str>,
+    #[segment(field)]
+    source: Option<std::rc::Rc<str>>,
+}
+
+#[test]
+fn string_types() {
+    let metric = SharedStrings {
+        timestamp: Duration::from_nanos(0),
+        service: "api gateway".into(),
+        level: Some("warn".into()),
+        message: "disk \"full\"".into(),
+        source: Some("df".into()),
+    };
+    assert_eq!(metric.to_lineproto().unwrap(), "log,level=warn,service=api\\ gateway message=\"disk \\\"full\\\"\",source=\"df\" 0");
+    assert_eq!(metric.fields()[1].value, segment::FieldValue::Str("df".to_string()));
+    assert_eq!(round_trip(&metric), Ok(metric));
+}
+
+#[derive(Metric)]
+#[segment(measurement="request")]
+struct View<'a, T> {
+    #[segment(tag)]
+    path: std::borrow::Cow<'a, str>,
+    #[segment(field)]
+    body: &'a str,
+    #[segment(field)]
+    size: Option<std::borrow::Cow<'a, str>>,
+    extra: T,
+}
+
+#[test]
+fn borrowed_views() {
+    let record = (String::from("/a b"), String::from("hello"));
+    let metric = View {
+        path: std::borrow::Cow::Borrowed(record.0.as_str()),
+        body: &record.1,
+        size: None,
+        extra: (),
+    };
+    assert_eq!(metric.extra, ());
+    assert_eq!(metric.to_lineproto().unwrap(), "request,path=/a\\ b body=\"hello\"");
+}
+
+/// A field type defined outside of segment.
+struct Bytes(u64);
+
+impl segment::ToFieldValue for Bytes {
+    fn to_field_value(&self) -> Option<segment::FieldValue> {
+        self.0.to_field_value()
+    }
+}
+
+type Host = std::string::String;
+
+#[derive(Metric)]
+#[segment(measurement="disk")]
+struct Disk {
+    #[segment(tag)]
+    host: Host,
+    #[segment(tag)]
+    device: std::num::NonZeroU8,
+    #[segment(field)]
+    used: Bytes,
+    #[segment(field, unsigned)]
+    free: Option<Bytes>,
+    #[segment(field)]
+    inodes: std::num::Wrapping<u16>,
+    #[segment(field)]
+    delta: i8,
+    #[segment(field)]
+    label: std::string::String,
+}
+
+#[test]
+fn value_traits() {
+    let metric = Disk {
+        host: "a".to_string(),
+        device: std::num::NonZeroU8::new(3).unwrap(),
+        used: Bytes(1024),
+        free: Some(Bytes(2048)),
+        inodes: std::num::Wrapping(7),
+        delta: -4,
+        label: "root".to_string(),
+    };
+    assert_eq!(metric.to_lineproto().unwrap(), "disk,device=3,host=a used=1024i,free=2048u,inodes=7i,delta=-4i,label=\"root\"");
+    assert_eq!(metric.fields()[0].value, segment::FieldValue::UInt64(1024));
+    assert_eq!(metric.tags()[0].value, "3");
+
+    let metric = Disk { free: None, ..metric };
+    let mut s = String::new();
+    metric.build_with(&mut s, &Options::default().unsigned(Unsigned::Native)).unwrap();
+    assert_eq!(s, "disk,device=3,host=a used=1024u,inodes=7u,delta=-4i,label=\"root\"");
+}
+
+#[derive(Debug, PartialEq, Metric, FromLineProto)]
+#[segment(measurement="net")]
+struct Interface {
+    #[segment(tag)]
+    index: u16,
+    #[segment(tag)]
+    queue: std::num::NonZeroUsize,
+    #[segment(tag)]
+    vlan: std::num::Wrapping<i16>,
+    #[segment(field)]
+    errors: u8,
+    #[segment(field)]
+    delta: i8,
+    #[segment(field)]
+    offset: isize,
+    #[segment(field)]
+    packets: usize,
+    #[segment(field)]
+    drops: std::num::NonZeroU32,
+    #[segment(field)]
+    retries: std::num::Wrapping<u16>,
+}
+
+#[test]
+fn value_traits_round_trip() {
+    let metric = Interface {
+        index: 2,
+        queue: std::num::NonZeroUsize::new(4).unwrap(),
+        vlan: std::num::Wrapping(-1),
+        errors: 255,
+        delta: -128,
+        offset: -5,
+        packets: 1 << 40,
+        drops: std::num::NonZeroU32::new(9).unwrap(),
+        retries: std::num::Wrapping(3),
+    };
+    assert_eq!(round_trip(&metric), Ok(metric));
+
+    // Values out of range for the member's type are rejected.
+    assert_eq!(
+        Interface::from_lineproto("net,index=2,queue=4,vlan=1 errors=256i,delta=0i,offset=0i,packets=0i,drops=1i,retries=0i"),
+        Err(DecodeError::InvalidField("errors".to_string())),
+    );
+    assert_eq!(
+        Interface::from_lineproto("net,index=2,queue=0,vlan=1 errors=0i,delta=0i,offset=0i,packets=0i,drops=1i,retries=0i"),
+        Err(DecodeError::InvalidTag("queue".to_string())),
+    );
+    assert_eq!(
+        Interface::from_lineproto("net,index=2,queue=4,vlan=1 errors=0i,delta=0i,offset=0i,packets=0i,drops=0i,retries=0i"),
+        Err(DecodeError::InvalidField("drops".to_string())),
+    );
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, segment::TagValue)]
+enum Status {
+    #[segment(rename = "ok")]
+    Healthy,
+    #[segment(rename = "read only")]
+    ReadOnly,
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Tier {
+    Hot,
+    Cold,
+}
+
+impl std::fmt::Display for Tier {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Tier::Hot => "hot,ssd",
+            Tier::Cold => "cold",
+        })
+    }
+}
+
+impl std::str::FromStr for Tier {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        match s {
+            "hot,ssd" => Ok(Tier::Hot),
+            "cold" => Ok(Tier::Cold),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Metric, FromLineProto)]
+#[segment(measurement="volume")]
+struct Volume {
+    #[segment(tag)]
+    status: Status,
+    #[segment(tag, display)]
+    tier: Tier,
+    #[segment(tag, display)]
+    backup: Option<Tier>,
+    #[segment(field)]
+    last: Status,
+    #[segment(field, display)]
+    addr: std::net::Ipv4Addr,
+}
+
+#[test]
+fn enum_values() {
+    let metric = Volume {
+        status: Status::ReadOnly,
+        tier: Tier::Hot,
+        backup: None,
+        last: Status::Healthy,
+        addr: std::net::Ipv4Addr::new(10, 0, 0, 1),
+    };
+    assert_eq!(metric.to_lineproto().unwrap(), "volume,status=read\\ only,tier=hot\\,ssd last=\"ok\",addr=\"10.0.0.1\"");
+    assert_eq!(round_trip(&metric), Ok(metric.clone()));
+
+    // Tags are the same whether built into a line or listed.
+    let metric = Volume { status: Status::Failed, backup: Some(Tier::Cold), ..metric };
+    let tags: Vec<(String, String)> = metric.tags().into_iter().map(|t| (t.name, t.value)).collect();
+    assert_eq!(tags, vec![
+        ("backup".to_string(), "cold".to_string()),
+        ("status".to_string(), "Failed".to_string()),
+        ("tier".to_string(), "hot,ssd".to_string()),
+    ]);
+    assert_eq!(metric.to_lineproto().unwrap(), "volume,backup=cold,status=Failed,tier=hot\\,ssd last=\"ok\",addr=\"10.0.0.1\"");
+    assert_eq!(round_trip(&metric), Ok(metric));
+
+    assert_eq!(
+        Volume::from_lineproto("volume,status=broken,tier=cold last=\"ok\",addr=\"10.0.0.1\""),
+        Err(DecodeError::InvalidTag("status".to_string())),
+    );
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+fn unknown_host() -> String {
+    "unknown".to_string()
+}
+
+#[derive(Debug, PartialEq, Metric, FromLineProto)]
+#[segment(measurement="requests")]
+struct Requests {
+    #[segment(tag, skip_if = "String::is_empty", default = "unknown_host")]
+    host: String,
+    #[segment(tag, skip_if = "Option::is_none")]
+    route: Option<String>,
+    #[segment(field, skip_if = "is_zero", default)]
+    errors: u64,
+    #[segment(field, skip_if = "String::is_empty", default)]
+    note: String,
+    #[segment(field)]
+    total: u64,
+}
+
+#[test]
+fn skipped_members() {
+    let metric = Requests {
+        host: "a".to_string(),
+        route: None,
+        errors: 0,
+        note: String::new(),
+        total: 12,
+    };
+    assert_eq!(metric.to_lineproto().unwrap(), "requests,host=a total=12i");
+    assert_eq!(metric.fields().len(), 1);
+    assert_eq!(round_trip(&metric), Ok(metric));
+
+    let metric = Requests {
+        host: String::new(),
+        route: Some("/".to_string()),
+        errors: 3,
+        note: "slow".to_string(),
+        total: 12,
+    };
+    assert_eq!(metric.to_lineproto().unwrap(), "requests,route=/ errors=3i,note=\"slow\",total=12i");
+    assert_eq!(metric.tags().len(), 1);
+    assert_eq!(Requests::from_lineproto("requests errors=3i,note=\"slow\",total=12i"), Ok(Requests {
+        host: "unknown".to_string(),
+        route: None,
+        ..metric
+    }));
+    assert_eq!(
+        Requests::from_lineproto("requests errors=3i"),
+        Err(DecodeError::MissingField("total".to_string())),
+    );
+}
+
+#[derive(segment::Flatten)]
+struct Origin {
+    #[segment(tag)]
+    service: &'static str,
+    #[segment(tag, rename = "az")]
+    region: String,
+    #[segment(tag, skip_if = "Option::is_none")]
+    canary: Option<bool>,
+}
+
+#[derive(segment::Flatten)]
+struct QueueCounters {
+    #[segment(field, skip_if = "is_zero")]
+    retries: u64,
+    #[segment(field)]
+    dropped: Option<u64>,
+}
+
+#[derive(Metric)]
+#[segment(measurement="queue")]
+struct Queue {
+    #[segment(tag)]
+    name: String,
+    #[segment(tag)]
+    host: String,
+    #[segment(flatten)]
+    origin: Origin,
+    #[segment(flatten)]
+    counters: Option<QueueCounters>,
+    #[segment(field, skip_if = "is_zero")]
+    depth: u64,
+}
+
+#[test]
+fn flattened_members() {
+    let metric = Queue {
+        name: "jobs".to_string(),
+        host: "b".to_string(),
+        origin: Origin { service: "api", region: "eu west".to_string(), canary: Some(true) },
+        counters: Some(QueueCounters { retries: 2, dropped: Some(1) }),
+        depth: 5,
+    };
+    assert_eq!(
+        metric.to_lineproto().unwrap(),
+        "queue,az=eu\\ west,canary=true,host=b,name=jobs,service=api depth=5i,retries=2i,dropped=1i",
+    );
+    let tags: Vec<String> = metric.tags().into_iter().map(|t| t.name).collect();
+    assert_eq!(tags, vec!["az", "canary", "host", "name", "service"]);
+    let fields: Vec<String> = metric.fields().into_iter().map(|f| f.name).collect();
+    assert_eq!(fields, vec!["depth", "retries", "dropped"]);
+
+    // Separators hold when the metric's own fields are all omitted.
+    let metric = Queue {
+        origin: Origin { canary: None, ..metric.origin },
+        counters: Some(QueueCounters { retries: 0, dropped: Some(1) }),
+        depth: 0,
+        ..metric
+    };
+    assert_eq!(metric.to_lineproto().unwrap(), "queue,az=eu\\ west,host=b,name=jobs,service=api dropped=1i");
+
+    let metric = Queue { counters: None, ..metric };
+    let mut s = String::new();
+    assert_eq!(metric.build(&mut s).unwrap(), 0);
+    assert_eq!(s, "");
+}
+
+#[derive(segment::Flatten)]
+struct Placement {
+    #[segment(tag)]
+    host: String,
+    #[segment(tag)]
+    zone: String,
+}
+
+#[derive(Metric)]
+#[segment(measurement="job")]
+struct Job {
+    #[segment(tag)]
+    host: Option<String>,
+    #[segment(flatten)]
+    placement: Placement,
+    #[segment(flatten)]
+    fallback: Placement,
+    #[segment(tags)]
+    labels: std::collections::BTreeMap<String, String>,
+    #[segment(field)]
+    value: i64,
+}
+
+#[test]
+fn flattened_tag_collisions() {
+    let metric = Job {
+        host: Some("h".to_string()),
+        placement: Placement { host: "g".to_string(), zone: "a".to_string() },
+        fallback: Placement { host: "f".to_string(), zone: "b".to_string() },
+        labels: vec![("zone".to_string(), "c".to_string()), ("team".to_string(), "ops".to_string())].into_iter().collect(),
+        value: 1,
+    };
+    assert_eq!(metric.to_lineproto().unwrap(), "job,host=h,team=ops,zone=a value=1i");
+    let tags: Vec<(String, String)> = metric.tags().into_iter().map(|t| (t.name, t.value)).collect();
+    assert_eq!(tags, vec![
+        ("host".to_string(), "h".to_string()),
+        ("team".to_string(), "ops".to_string()),
+        ("zone".to_string(), "a".to_string()),
+    ]);
+
+    // An omitted tag of the metric leaves its key to a flattened member.
+    let metric = Job { host: None, ..metric };
+    assert_eq!(metric.to_lineproto().unwrap(), "job,host=g,team=ops,zone=a value=1i");
+}
+
+#[derive(Metric)]
+#[segment(measurement="pod")]
+struct Pod {
+    #[segment(tag)]
+    namespace: String,
+    #[segment(tags)]
+    labels: std::collections::BTreeMap<String, String>,
+    #[segment(tags)]
+    annotations: std::collections::HashMap<&'static str, String>,
+    #[segment(field)]
+    restarts: u32,
+    #[segment(fields)]
+    usage: std::collections::BTreeMap<String, f64>,
+}
+
+#[test]
+fn map_members() {
+    let metric = Pod {
+        namespace: "default".to_string(),
+        labels: vec![
+            ("app".to_string(), "web server".to_string()),
+            ("namespace".to_string(), "shadowed".to_string()),
+            ("empty".to_string(), String::new()),
+            ("zone".to_string(), "b".to_string()),
+        ].into_iter().collect(),
+        annotations: vec![("app", "taken".to_string()), ("owner", "ops".to_string())].into_iter().collect(),
+        restarts: 2,
+        usage: vec![
+            ("cpu".to_string(), 0.5),
+            ("restarts".to_string(), 9.0),
+            ("mem".to_string(), f64::NAN),
+        ].into_iter().collect(),
+    };
+    assert_eq!(
+        metric.to_lineproto().unwrap(),
+        "pod,app=web\\ server,namespace=default,owner=ops,zone=b restarts=2i,cpu=0.5",
+    );
+    let tags: Vec<(String, String)> = metric.tags().into_iter().map(|t| (t.name, t.value)).collect();
+    assert_eq!(tags, vec![
+        ("app".to_string(), "web server".to_string()),
+        ("namespace".to_string(), "default".to_string()),
+        ("owner".to_string(), "ops".to_string()),
+        ("zone".to_string(), "b".to_string()),
+    ]);
+    let fields: Vec<String> = metric.fields().into_iter().map(|f| f.name).collect();
+    assert_eq!(fields, vec!["restarts", "cpu", "mem"]);
+}
+
+#[derive(Debug, PartialEq, Metric, FromLineProto)]
+#[segment(measurement="deploy", tag(env = "prod", cluster = "eu west"), field(schema_version = 3, ratio = -0.5, note = "v\"2\""))]
+struct Deploy {
+    #[segment(tag)]
+    app: String,
+    #[segment(tag)]
+    region: String,
+    #[segment(field)]
+    replicas: u32,
+}
+
+#[derive(segment::Flatten)]
+#[segment(tag(team = "core"), field(sampled = true))]
+struct Ownership {
+    #[segment(tag)]
+    owner: String,
+}
+
+#[derive(Metric)]
+#[segment(measurement="build", tag(ci = "yes"))]
+struct Build {
+    #[segment(flatten)]
+    ownership: Ownership,
+    #[segment(field)]
+    ok: bool,
+}
+
+#[test]
+fn constant_members() {
+    let metric = Deploy { app: "web".to_string(), region: "b".to_string(), replicas: 3 };
+    assert_eq!(
+        metric.to_lineproto().unwrap(),
+        "deploy,app=web,cluster=eu\\ west,env=prod,region=b schema_version=3i,ratio=-0.5,note=\"v\\\"2\\\"\",replicas=3i",
+    );
+    let tags: Vec<String> = metric.tags().into_iter().map(|t| t.name).collect();
+    assert_eq!(tags, vec!["app", "cluster", "env", "region"]);
+    assert_eq!(metric.fields()[1].value, segment::FieldValue::Float64(-0.5));
+    assert_eq!(round_trip(&metric), Ok(metric));
+
+    // Constant members are checked when present, but not required.
+    assert_eq!(
+        Deploy::from_lineproto("deploy,app=web,region=b replicas=3i"),
+        Ok(Deploy { app: "web".to_string(), region: "b".to_string(), replicas: 3 }),
+    );
+    assert_eq!(
+        Deploy::from_lineproto("deploy,app=web,env=dev,region=b replicas=3i"),
+        Err(DecodeError::InvalidTag("env".to_string())),
+    );
+
+    let metric = Build { ownership: Ownership { owner: "ana".to_string() }, ok: true };
+    assert_eq!(metric.to_lineproto().unwrap(), "build,ci=yes,owner=ana,team=core ok=t,sampled=t");
+}
+
+#[derive(Debug, PartialEq, Metric, FromLineProto)]
+struct TenantUsage {
+    #[segment(measurement)]
+    tenant: String,
+    #[segment(tag)]
+    host: String,
+    #[segment(field)]
+    bytes: u64,
+}
+
+fn usage_measurement(metric: &Usage) -> String {
+    format!("usage_{}", metric.tenant)
+}
+
+#[derive(Debug, PartialEq, Metric, FromLineProto)]
+#[segment(measurement_fn = "usage_measurement")]
+struct Usage {
+    #[segment(tag)]
+    tenant: String,
+    #[segment(field)]
+    bytes: u64,
+}
+
+#[test]
+fn dynamic_measurements() {
+    let metric = TenantUsage { tenant: "acme, inc".to_string(), host: "a".to_string(), bytes: 10 };
+    assert_eq!(metric.measurement(), "acme, inc");
+    assert_eq!(metric.to_lineproto().unwrap(), "acme\\,\\ inc,host=a bytes=10i");
+    assert_eq!(round_trip(&metric), Ok(metric));
+
+    let metric = TenantUsage { tenant: String::new(), host: "a".to_string(), bytes: 10 };
+    let mut s = String::new();
+    match metric.build(&mut s) {
+        Err(Error::EmptyMeasurement) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(s, "");
+
+    let metric = Usage { tenant: "acme".to_string(), bytes: 10 };
+    assert_eq!(metric.measurement(), "usage_acme");
+    assert_eq!(metric.to_lineproto().unwrap(), "usage_acme,tenant=acme bytes=10i");
+    assert_eq!(round_trip(&metric), Ok(metric));
+    assert_eq!(
+        Usage::from_lineproto("usage_other,tenant=acme bytes=10i"),
+        Err(DecodeError::Measurement("usage_other".to_string())),
+    );
+}
+
+#[test]
+fn empty_tag_values() {
+    let metric = MultiTag { timestamp: Duration::from_nanos(0), host: String::new(), cpu: "0".to_string(), value: 1.0 };
+    let mut s = String::new();
+    match metric.build(&mut s) {
+        Err(Error::EmptyTagValue(name)) => assert_eq!(name, "host"),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(s, "");
+
+    // Tags gathered at runtime are checked as well.
+    let metric = Queue {
+        name: "jobs".to_string(),
+        host: "b".to_string(),
+        origin: Origin { service: "", region: "eu".to_string(), canary: None },
+        counters: None,
+        depth: 5,
+    };
+    match metric.build(&mut s) {
+        Err(Error::EmptyTagValue(name)) => assert_eq!(name, "service"),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(s, "");
+}
@@ -0,0 +1,201 @@
+use std::borrow::Cow;
+use std::time::Duration;
+
+use segment::{parse_line, Field, FieldValue, LineReader, Metric, Options, ParseErrorKind, Point, Tag, Unsigned, Value};
+
+#[derive(Metric)]
+#[segment(measurement="cpu")]
+struct Sample {
+    #[segment(time)]
+    timestamp: Duration,
+    #[segment(tag)]
+    host: String,
+    #[segment(tag)]
+    region: String,
+    #[segment(field)]
+    load: f64,
+    #[segment(field)]
+    count: u64,
+    #[segment(field)]
+    descr: String,
+}
+
+#[test]
+fn minimal_line() {
+    let line = parse_line("cpu value=42.0 0").unwrap();
+
+    assert_eq!(line.measurement(), "cpu");
+    assert_eq!(line.tags().count(), 0);
+    assert_eq!(line.field("value"), Some(Value::Float(42.0)));
+    assert_eq!(line.timestamp(), Some(0));
+}
+
+#[test]
+fn round_trip_derived_metric() {
+    let metric = Sample {
+        timestamp: Duration::from_nanos(1_556_813_561_098_000_000),
+        host: "my host".to_string(),
+        region: "us=west\nfoo".to_string(),
+        load: 0.75,
+        count: u64::MAX,
+        descr: "say \"hi\"\nbye".to_string(),
+    };
+    let mut s = String::new();
+    metric.build_with(&mut s, &Options::default().unsigned(Unsigned::Native)).unwrap();
+
+    let line = parse_line(&s).unwrap();
+    assert_eq!(line.measurement(), "cpu");
+    let tags: Vec<_> = line.tags().collect();
+    assert_eq!(tags, vec![
+        (Cow::from("host"), Cow::from("my host")),
+        (Cow::from("region"), Cow::from("us=west\nfoo")),
+    ]);
+    let fields: Vec<_> = line.fields().collect();
+    assert_eq!(fields, vec![
+        (Cow::from("load"), Value::Float(0.75)),
+        (Cow::from("count"), Value::UInt(u64::MAX)),
+        (Cow::from("descr"), Value::Str(Cow::from("say \"hi\"\nbye"))),
+    ]);
+    assert_eq!(line.timestamp(), Some(1_556_813_561_098_000_000));
+}
+
+#[test]
+fn borrows_unescaped_values() {
+    let line = parse_line("cpu,host=a value=\"plain\",other=\"esc\\\"aped\"").unwrap();
+
+    assert!(matches!(line.tag("host"), Some(Cow::Borrowed("a"))));
+    assert!(matches!(line.field("value"), Some(Value::Str(Cow::Borrowed("plain")))));
+    assert!(matches!(line.field("other"), Some(Value::Str(Cow::Owned(_)))));
+    assert_eq!(line.timestamp(), None);
+}
+
+#[test]
+fn field_value_types() {
+    let line = parse_line("m a=1i,b=-2i,c=1.5,d=3,e=-4.5e3,f=\"\",g=7u,h=18446744073709551615i\n").unwrap();
+    let values: Vec<_> = line.fields().map(|(_, v)| v).collect();
+
+    assert_eq!(values, vec![
+        Value::Int(1),
+        Value::Int(-2),
+        Value::Float(1.5),
+        Value::Float(3.0),
+        Value::Float(-4500.0),
+        Value::Str(Cow::from("")),
+        Value::UInt(7),
+        Value::UInt(u64::MAX),
+    ]);
+}
+
+#[test]
+fn escaped_separators_in_keys() {
+    let line = parse_line("my\\ measure,tag\\ key=tag\\,value field\\=key=1i -5").unwrap();
+
+    assert_eq!(line.measurement(), "my measure");
+    assert_eq!(line.tag("tag key"), Some(Cow::from("tag,value")));
+    assert_eq!(line.field("field=key"), Some(Value::Int(1)));
+    assert_eq!(line.timestamp(), Some(-5));
+}
+
+#[test]
+fn round_trip_backslashes() {
+    // A literal backslash followed by `n`, and a trailing backslash, are not
+    // mistaken for a newline or for escaping the following separator.
+    for value in &["a\\n", "a\\", "a\\\n b"] {
+        let point = Point::new(format!("m{}", value)).tag(format!("k{}", value), *value).field("v", 1i64);
+        let s = point.to_lineproto().unwrap();
+        let line = parse_line(&s).unwrap();
+        assert_eq!(line.measurement(), format!("m{}", value), "parsing {:?}", s);
+        assert_eq!(line.tag(&format!("k{}", value)), Some(Cow::from(*value)), "parsing {:?}", s);
+        assert_eq!(line.field("v"), Some(Value::Int(1)));
+    }
+    assert_eq!(
+        Point::new("cpu").tag("host", "a\\").field("v", 1i64).to_lineproto().unwrap(),
+        "cpu,host=a\\\\ v=1i",
+    );
+}
+
+#[test]
+fn reports_error_columns() {
+    let cases = vec![
+        ("", ParseErrorKind::EmptyMeasurement, 0),
+        (",host=a value=1", ParseErrorKind::EmptyMeasurement, 0),
+        ("cpu", ParseErrorKind::MissingFields, 3),
+        ("cpu,host=a", ParseErrorKind::MissingFields, 10),
+        ("cpu,host value=1", ParseErrorKind::InvalidTag, 4),
+        ("cpu,host= value=1", ParseErrorKind::EmptyTagValue, 9),
+        ("cpu value", ParseErrorKind::InvalidField, 4),
+        ("cpu value=1,=2", ParseErrorKind::InvalidField, 12),
+        ("cpu value=abc", ParseErrorKind::InvalidFieldValue, 10),
+        ("cpu value=NaN", ParseErrorKind::InvalidFieldValue, 10),
+        ("cpu value=-1u", ParseErrorKind::InvalidFieldValue, 10),
+        ("cpu v=+1i", ParseErrorKind::InvalidFieldValue, 6),
+        ("cpu v=+1u", ParseErrorKind::InvalidFieldValue, 6),
+        ("cpu v=+1", ParseErrorKind::InvalidFieldValue, 6),
+        ("cpu value=\"abc", ParseErrorKind::UnterminatedString, 10),
+        ("cpu value=1 12x", ParseErrorKind::InvalidTimestamp, 12),
+        ("cpu value=1 +12", ParseErrorKind::InvalidTimestamp, 12),
+        ("cpu value=1 12 13", ParseErrorKind::TrailingCharacters, 14),
+    ];
+
+    for (line, kind, column) in cases {
+        let err = parse_line(line).unwrap_err();
+        assert_eq!((err.kind, err.column), (kind, column), "parsing {:?}", line);
+    }
+}
+
+#[test]
+fn reader_skips_comments_and_blank_lines() {
+    let input = "# a comment\n\ncpu,host=a value=1i 10\n   \n  # indented comment\r\nmem free=2.5\n";
+    let points: Vec<_> = LineReader::new(input.as_bytes()).collect();
+
+    assert_eq!(points, vec![
+        Ok(Point {
+            measurement: "cpu".to_string(),
+            tags: vec![Tag { name: "host".to_string(), value: "a".to_string() }],
+            fields: vec![Field { name: "value".to_string(), value: FieldValue::Int64(1) }],
+            timestamp: Some(10),
+        }),
+        Ok(Point {
+            measurement: "mem".to_string(),
+            tags: vec![],
+            fields: vec![Field { name: "free".to_string(), value: FieldValue::Float64(2.5) }],
+            timestamp: None,
+        }),
+    ]);
+}
+
+#[test]
+fn reader_recovers_from_bad_lines() {
+    let input: &[u8] = b"cpu value=1i\ncpu value=\n  cpu,host value=1\ncpu value=\xff\ncpu value=2i";
+    let mut reader = LineReader::new(input);
+
+    assert_eq!(reader.next().unwrap().unwrap().fields[0].value, FieldValue::Int64(1));
+
+    let err = reader.next().unwrap().unwrap_err();
+    assert_eq!((err.kind, err.line, err.column), (ParseErrorKind::InvalidFieldValue, 2, 10));
+
+    let err = reader.next().unwrap().unwrap_err();
+    assert_eq!((err.kind, err.line, err.column), (ParseErrorKind::InvalidTag, 3, 6));
+
+    let err = reader.next().unwrap().unwrap_err();
+    assert_eq!((err.kind, err.line, err.column), (ParseErrorKind::InvalidUtf8, 4, 10));
+
+    assert_eq!(reader.next().unwrap().unwrap().fields[0].value, FieldValue::Int64(2));
+    assert!(reader.next().is_none());
+    assert_eq!(reader.line_number(), 5);
+}
+
+#[test]
+fn bool_spellings() {
+    let line = parse_line("m a=t,b=T,c=true,d=True,e=TRUE,f=f,g=F,h=false,i=False,j=FALSE").unwrap();
+    let values: Vec<_> = line.fields().map(|(_, v)| v).collect();
+
+    assert_eq!(values[..5], vec![Value::Bool(true); 5][..]);
+    assert_eq!(values[5..], vec![Value::Bool(false); 5][..]);
+
+    let err = parse_line("m a=tRUE").unwrap_err();
+    assert_eq!(err.kind, ParseErrorKind::InvalidFieldValue);
+
+    let point = Point::new("m").field("a", true).field("b", false);
+    assert_eq!(point.to_lineproto().unwrap(), "m a=t,b=f");
+}
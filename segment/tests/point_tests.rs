@@ -0,0 +1,139 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use segment::{parse_line, Error, FieldValue, Metric, NonFinite, Options, Point, Precision};
+
+#[test]
+fn builder_sorts_tags() {
+    let point = Point::new("cpu")
+        .tag("region", "us-west")
+        .tag("host", "localhost")
+        .field("value", 42.0)
+        .field("count", 7u64)
+        .field("descr", "idle")
+        .timestamp(Duration::from_nanos(10));
+
+    let mut s = String::new();
+    point.build(&mut s).unwrap();
+
+    assert_eq!(s, "cpu,host=localhost,region=us-west value=42.0,count=7i,descr=\"idle\" 10");
+}
+
+#[test]
+fn builder_replaces_repeated_keys() {
+    let point = Point::new("cpu")
+        .tag("host", "a")
+        .tag("dc", "eu")
+        .tag("host", "b")
+        .field("value", 1i64)
+        .field("count", 2i64)
+        .field("value", 3.5);
+
+    assert_eq!(point.tags.len(), 2);
+    assert_eq!(point.fields.len(), 2);
+    assert_eq!(point.to_lineproto().unwrap(), "cpu,dc=eu,host=b value=3.5,count=2i");
+}
+
+#[test]
+fn optional_timestamp() {
+    let point = Point::new("cpu").field("value", 1i64);
+
+    assert_eq!(point.to_lineproto().unwrap(), "cpu value=1i");
+    assert_eq!(point.time(), None);
+}
+
+#[test]
+fn parsed_point_round_trip() {
+    let line = "cpu,host=b,dc=a value=1.5,name=\"x y\" 1556813561098000000";
+    let point = Point::from(parse_line(line).unwrap());
+
+    assert_eq!(point.fields[1].value, FieldValue::Str("x y".to_string()));
+    assert_eq!(point.to_lineproto().unwrap(), "cpu,dc=a,host=b value=1.5,name=\"x y\" 1556813561098000000");
+}
+
+#[test]
+fn build_validates_names() {
+    let invalid = vec![
+        (Point::new("").field("value", 1.0), "empty measurement name"),
+        (Point::new("cpu"), "no fields defined for metric"),
+        (Point::new("cpu").tag("", "x").field("value", 1.0), "invalid key \"\""),
+        (Point::new("cpu").tag("host", "").field("value", 1.0), "empty value for tag \"host\""),
+        (Point::new("cpu").field("", 1.0), "invalid key \"\""),
+    ];
+
+    for (point, msg) in invalid {
+        let mut s = String::new();
+        let err = point.build(&mut s).unwrap_err();
+        assert_eq!(err.to_string(), msg);
+        assert_eq!(s, "");
+    }
+}
+
+#[test]
+fn non_finite_fields_are_errors() {
+    let point = Point::new("cpu").field("a", 1i64).field("b", f64::INFINITY);
+
+    let options = Options::default().non_finite(NonFinite::Error);
+    let mut s = String::from("existing\n");
+    match point.build_with(&mut s, &options) {
+        Err(Error::NonFiniteFloat) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(s, "existing\n");
+}
+
+#[test]
+fn non_finite_fields_are_skipped() {
+    let point = Point::new("cpu").field("a", f64::NAN).field("b", 1i64).field("c", f32::NEG_INFINITY);
+    assert_eq!(point.to_lineproto().unwrap(), "cpu b=1i");
+
+    let point = Point::new("cpu").field("a", f64::NAN);
+    let mut s = String::new();
+    assert_eq!(point.build(&mut s).unwrap(), 0);
+    assert_eq!(s, "");
+}
+
+#[test]
+fn display_field_values() {
+    assert_eq!(FieldValue::UInt64(u64::MAX).to_string(), "18446744073709551615u");
+    assert_eq!(FieldValue::UInt32(7).to_string(), "7u");
+    assert_eq!(FieldValue::Int64(-7).to_string(), "-7i");
+    assert_eq!(FieldValue::Float64(1.5).to_string(), "1.5");
+    assert_eq!(FieldValue::Float64(f64::NAN).to_string(), "NaN");
+    assert_eq!(FieldValue::Float32(f32::NEG_INFINITY).to_string(), "-inf");
+    assert_eq!(FieldValue::Str("a \"b\"".to_string()).to_string(), "\"a \\\"b\\\"\"");
+    assert_eq!(format!("{}", FieldValue::Bool(true)), "t");
+}
+
+#[test]
+fn timestamp_precision() {
+    let point = Point::new("cpu").field("a", 1i64).timestamp(Duration::new(5, 999_999_999));
+    assert_eq!(point.to_lineproto().unwrap(), "cpu a=1i 5999999999");
+
+    let mut s = String::new();
+    point.build_with(&mut s, &Options::default().precision(Precision::Seconds)).unwrap();
+    assert_eq!(s, "cpu a=1i 5");
+
+    // Negative timestamps are rounded towards the past as well.
+    let mut point = point;
+    point.timestamp = Some(-1_500_000);
+    let mut s = String::new();
+    point.build_with(&mut s, &Options::default().precision(Precision::Milliseconds)).unwrap();
+    assert_eq!(s, "cpu a=1i -2");
+}
+
+#[test]
+fn out_of_range_timestamps() {
+    let point = Point::new("cpu").field("a", 1i64);
+    match point.clone().try_timestamp(u64::MAX) {
+        Err(Error::InvalidTimestamp) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    let late = UNIX_EPOCH + Duration::from_secs(300 * 365 * 24 * 3600);
+    assert!(point.clone().try_timestamp(late).is_err());
+    assert_eq!(point.clone().try_timestamp(5u64).unwrap().to_lineproto().unwrap(), "cpu a=1i 5");
+
+    // The infallible setter leaves the point without a timestamp.
+    let point = point.timestamp(u64::MAX);
+    assert_eq!(point.timestamp, None);
+    assert_eq!(point.to_lineproto().unwrap(), "cpu a=1i");
+}
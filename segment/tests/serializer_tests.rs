@@ -0,0 +1,136 @@
+use std::cell::Cell;
+use std::collections::BTreeMap;
+
+use segment::{Error, Field, FieldValue, Metric, Options, Point, Precision, Result, Serializer, Sink, Tag};
+
+#[derive(Metric)]
+#[segment(measurement="cpu", tag(env = "prod"))]
+struct Cpu {
+    #[segment(tag)]
+    host: String,
+    #[segment(tag)]
+    core: Option<u32>,
+    #[segment(field)]
+    value: f64,
+    #[segment(time, precision = "s")]
+    timestamp: i64,
+}
+
+#[derive(Metric)]
+#[segment(measurement="pod")]
+struct Pod {
+    #[segment(tags)]
+    labels: BTreeMap<String, String>,
+    #[segment(field)]
+    restarts: u32,
+}
+
+/// A metric implemented by hand, written through the default
+/// `build_with_tags`, which counts the lines it builds.
+#[derive(Default)]
+struct Manual {
+    built: Cell<usize>,
+}
+
+impl Metric for Manual {
+    fn time(&self) -> Option<i64> {
+        None
+    }
+
+    fn measurement(&self) -> String {
+        "manual".to_string()
+    }
+
+    fn fields(&self) -> Vec<Field> {
+        vec![Field { name: "value".to_string(), value: FieldValue::Int64(1) }]
+    }
+
+    fn tags(&self) -> Vec<Tag> {
+        vec![Tag { name: "host".to_string(), value: "own".to_string() }]
+    }
+
+    fn build_with(&self, buffer: &mut dyn Sink, options: &Options) -> Result<usize> {
+        self.built.set(self.built.get() + 1);
+        let mut point = Point::new(self.measurement());
+        point.tags = self.tags();
+        point.fields = self.fields();
+        point.timestamp = self.time();
+        point.build_with(buffer, options)
+    }
+}
+
+fn agent() -> Serializer {
+    Serializer::new()
+        .tag("host", "agent")
+        .tag("agent_version", "1.2")
+        .tag("zone", "b")
+        .tag("dc", "eu")
+        .tag("dc", "eu west")
+}
+
+#[test]
+fn default_tags_are_merged() {
+    let serializer = agent();
+    let names: Vec<&str> = serializer.default_tags().iter().map(|t| t.name.as_str()).collect();
+    assert_eq!(names, vec!["agent_version", "dc", "host", "zone"]);
+
+    let metric = Cpu { host: "a".to_string(), core: Some(1), value: 0.5, timestamp: 7_000_000_000 };
+    assert_eq!(
+        serializer.to_lineproto(&metric).unwrap(),
+        "cpu,agent_version=1.2,core=1,dc=eu\\ west,env=prod,host=a,zone=b value=0.5 7",
+    );
+    // Without defaults, derived metrics are written as before.
+    assert_eq!(Serializer::new().to_lineproto(&metric).unwrap(), metric.to_lineproto().unwrap());
+}
+
+#[test]
+fn omitted_tags_fall_back_to_defaults() {
+    let serializer = Serializer::new().tag("core", "all").tag("env", "dev");
+    let metric = Cpu { host: "a".to_string(), core: None, value: 0.5, timestamp: 0 };
+    assert_eq!(serializer.to_lineproto(&metric).unwrap(), "cpu,core=all,env=prod,host=a value=0.5 0");
+}
+
+#[test]
+fn runtime_tags_are_merged() {
+    let metric = Pod {
+        labels: vec![("host".to_string(), "node".to_string()), ("app".to_string(), "web".to_string())]
+            .into_iter().collect(),
+        restarts: 1,
+    };
+    assert_eq!(
+        agent().to_lineproto(&metric).unwrap(),
+        "pod,agent_version=1.2,app=web,dc=eu\\ west,host=node,zone=b restarts=1i",
+    );
+}
+
+#[test]
+fn other_metrics_are_merged() {
+    let manual = Manual::default();
+    assert_eq!(
+        agent().to_lineproto(&manual).unwrap(),
+        "manual,agent_version=1.2,dc=eu\\ west,host=own,zone=b value=1i",
+    );
+    assert_eq!(manual.built.get(), 0);
+    assert_eq!(manual.to_lineproto().unwrap(), "manual,host=own value=1i");
+    // Without defaults, the metric's own `build_with` is used.
+    assert_eq!(Serializer::new().to_lineproto(&manual).unwrap(), "manual,host=own value=1i");
+    assert_eq!(manual.built.get(), 2);
+
+    let point = Point::new("mem").tag("dc", "us").field("used", 3i64).timestamp(std::time::Duration::from_secs(2));
+    let serializer = agent().options(Options::default().precision(Precision::Seconds));
+    assert_eq!(
+        serializer.to_lineproto(&point).unwrap(),
+        "mem,agent_version=1.2,dc=us,host=agent,zone=b used=3i 2",
+    );
+}
+
+#[test]
+fn invalid_default_tags() {
+    let metric = Cpu { host: "a".to_string(), core: None, value: 0.5, timestamp: 0 };
+    let mut s = String::new();
+    match Serializer::new().tag("host", "").build(&metric, &mut s) {
+        Err(Error::EmptyTagValue(name)) => assert_eq!(name, "host"),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(s, "");
+}
@@ -0,0 +1,141 @@
+use std::fmt;
+use std::io::{self, Cursor, Write};
+
+use segment::{Error, LineWriter, Metric, NonFinite, Options, Point, Serializer};
+
+#[derive(Metric)]
+#[segment(measurement="disk")]
+struct Disk {
+    #[segment(tag)]
+    path: String,
+    #[segment(field)]
+    used: u64,
+    #[segment(field)]
+    ratio: f64,
+    #[segment(time)]
+    timestamp: i64,
+}
+
+fn disk(ratio: f64) -> Disk {
+    Disk { path: "/var log".to_string(), used: 42, ratio, timestamp: 1_000 }
+}
+
+/// A stream that fails every write.
+struct Broken;
+
+impl Write for Broken {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes a metric through `fmt::Display`.
+struct Line<'a>(&'a Disk);
+
+impl fmt::Display for Line<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Serializer::new().write_to_fmt(self.0, f).map(|_| ()).map_err(|_| fmt::Error)
+    }
+}
+
+#[test]
+fn byte_buffers() {
+    let expected = "disk,path=/var\\ log used=42i,ratio=0.5 1000";
+
+    let mut bytes = b"prefix ".to_vec();
+    assert_eq!(disk(0.5).build(&mut bytes).unwrap(), expected.len());
+    assert_eq!(bytes, format!("prefix {}", expected).into_bytes());
+
+    let mut bytes = Vec::new();
+    Point::new("cpu").tag("host", "a").field("value", 1.5).build(&mut bytes).unwrap();
+    assert_eq!(bytes, b"cpu,host=a value=1.5");
+
+    let mut bytes = Vec::new();
+    Serializer::new().tag("dc", "eu").build(&disk(0.5), &mut bytes).unwrap();
+    assert_eq!(bytes, b"disk,dc=eu,path=/var\\ log used=42i,ratio=0.5 1000");
+}
+
+#[test]
+fn failed_lines_are_rolled_back() {
+    let options = Options::default().non_finite(NonFinite::Error);
+    let mut bytes = b"kept".to_vec();
+    match disk(f64::NAN).build_with(&mut bytes, &options) {
+        Err(Error::NonFiniteFloat) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(bytes, b"kept");
+
+    // A field omitted by the default policy is rolled back along with its
+    // separator.
+    assert_eq!(disk(f64::NAN).build(&mut bytes).unwrap(), 33);
+    assert_eq!(bytes, b"keptdisk,path=/var\\ log used=42i 1000".to_vec());
+}
+
+#[test]
+fn line_writer() {
+    let mut writer = LineWriter::new(Cursor::new(Vec::new()));
+    assert_eq!(writer.write(&disk(0.5)).unwrap(), 44);
+    assert_eq!(writer.write(&Point::new("cpu").field("value", f64::NAN)).unwrap(), 0);
+    assert_eq!(writer.write(&Point::new("cpu").field("value", 1i64)).unwrap(), 13);
+    writer.flush().unwrap();
+    assert_eq!(
+        String::from_utf8(writer.into_inner().into_inner()).unwrap(),
+        "disk,path=/var\\ log used=42i,ratio=0.5 1000\ncpu value=1i\n",
+    );
+
+    let serializer = Serializer::new().tag("host", "a").options(Options::default().non_finite(NonFinite::Error));
+    let mut writer = LineWriter::with_serializer(Vec::new(), serializer);
+    writer.write(&Point::new("cpu").field("value", 1i64)).unwrap();
+    assert!(writer.write(&disk(f64::INFINITY)).is_err());
+    assert_eq!(writer.get_ref(), b"cpu,host=a value=1i\n");
+}
+
+#[test]
+fn stream_errors() {
+    let mut writer = LineWriter::new(Broken);
+    match writer.write(&disk(0.5)) {
+        Err(Error::Write(_)) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn fmt_writers() {
+    assert_eq!(Line(&disk(0.5)).to_string(), "disk,path=/var\\ log used=42i,ratio=0.5 1000");
+
+    let mut s = String::from("> ");
+    Serializer::new().write_to_fmt(&disk(2.0), &mut s).unwrap();
+    assert_eq!(s, "> disk,path=/var\\ log used=42i,ratio=2.0 1000");
+}
+
+#[cfg(feature = "bytes")]
+#[test]
+fn bytes_mut() {
+    let mut bytes = bytes::BytesMut::new();
+    disk(0.5).build(&mut bytes).unwrap();
+    assert_eq!(&bytes[..], b"disk,path=/var\\ log used=42i,ratio=0.5 1000");
+
+    let options = Options::default().non_finite(NonFinite::Error);
+    assert!(disk(f64::NAN).build_with(&mut bytes, &options).is_err());
+    assert_eq!(bytes.len(), 43);
+}
+
+#[test]
+fn trait_objects() {
+    let metrics: Vec<Box<dyn Metric>> = vec![Box::new(disk(0.5)), Box::new(Point::new("cpu").field("value", 1i64))];
+    let mut s = String::new();
+    for metric in &metrics {
+        metric.build(&mut s).unwrap();
+        s.push('\n');
+    }
+    assert_eq!(s, "disk,path=/var\\ log used=42i,ratio=0.5 1000\ncpu value=1i\n");
+
+    let metric: &dyn Metric = &*metrics[0];
+    let mut writer = LineWriter::with_serializer(Vec::new(), Serializer::new().tag("host", "a"));
+    writer.write(metric).unwrap();
+    assert_eq!(writer.get_ref(), b"disk,host=a,path=/var\\ log used=42i,ratio=0.5 1000\n");
+}
@@ -0,0 +1,123 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use segment::{DecodeError, Error, FromLineProto, Metric, Options, Point, Precision, Serializer};
+
+#[derive(Debug, PartialEq, Metric, FromLineProto)]
+#[segment(measurement="event")]
+struct Wall {
+    #[segment(time)]
+    timestamp: SystemTime,
+    #[segment(field)]
+    value: i64,
+}
+
+#[derive(Debug, PartialEq, Metric, FromLineProto)]
+#[segment(measurement="event")]
+struct Nanos {
+    #[segment(time, precision = "s")]
+    timestamp: i64,
+    #[segment(field)]
+    value: i64,
+}
+
+#[derive(Debug, PartialEq, Metric, FromLineProto)]
+#[segment(measurement="event")]
+struct Unsigned {
+    #[segment(time)]
+    timestamp: Option<u64>,
+    #[segment(field)]
+    value: i64,
+}
+
+#[test]
+fn system_time() {
+    let before_epoch = UNIX_EPOCH - Duration::new(1, 500_000_000);
+    let metric = Wall { timestamp: before_epoch, value: 1 };
+
+    assert_eq!(metric.time(), Some(-1_500_000_000));
+    assert_eq!(metric.to_lineproto().unwrap(), "event value=1i -1500000000");
+    assert_eq!(Wall::from_lineproto("event value=1i -1500000000"), Ok(metric));
+
+    let after_epoch = UNIX_EPOCH + Duration::from_nanos(1_556_813_561_098_000_000);
+    let metric = Wall { timestamp: after_epoch, value: 1 };
+    assert_eq!(metric.to_lineproto().unwrap(), "event value=1i 1556813561098000000");
+}
+
+#[test]
+fn negative_timestamps() {
+    let metric = Nanos { timestamp: -1_500_000_000, value: 1 };
+
+    // Seconds are rounded towards the past, even before the epoch.
+    assert_eq!(metric.to_lineproto().unwrap(), "event value=1i -2");
+    assert_eq!(
+        Nanos::from_lineproto("event value=1i -2"),
+        Ok(Nanos { timestamp: -2_000_000_000, value: 1 }));
+
+    assert_eq!(
+        Unsigned::from_lineproto("event value=1i -2"),
+        Err(DecodeError::InvalidTime(-2)));
+    assert_eq!(
+        Nanos::from_lineproto("event value=1i 9223372037"),
+        Err(DecodeError::InvalidTime(9_223_372_037)));
+}
+
+#[test]
+fn out_of_range_timestamps() {
+    let metric = Unsigned { timestamp: Some(u64::MAX), value: 1 };
+
+    let mut s = String::from("existing\n");
+    match metric.build(&mut s) {
+        Err(Error::InvalidTimestamp) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(s, "existing\n");
+
+    let metric = Unsigned { timestamp: None, value: 1 };
+    assert_eq!(metric.to_lineproto().unwrap(), "event value=1i");
+
+    // A time the metric has but that cannot be written is not dropped.
+    let metric = Wall { timestamp: UNIX_EPOCH + Duration::from_secs(300 * 365 * 24 * 3600), value: 1 };
+    assert_eq!(metric.time(), None);
+    match metric.build(&mut s) {
+        Err(Error::InvalidTimestamp) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    match Serializer::new().tag("host", "a").build(&metric, &mut s) {
+        Err(Error::InvalidTimestamp) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(s, "existing\n");
+}
+
+#[test]
+fn point_timestamps() {
+    let point = Point::new("event").field("value", 1i64).timestamp(-5i64);
+    assert_eq!(point.to_lineproto().unwrap(), "event value=1i -5");
+
+    let point = Point::new("event").field("value", 1i64).timestamp(UNIX_EPOCH + Duration::from_secs(3));
+    let mut s = String::new();
+    point.build_with(&mut s, &Options::default().precision(Precision::Seconds)).unwrap();
+    assert_eq!(s, "event value=1i 3");
+}
+
+#[cfg(feature = "chrono")]
+#[test]
+fn chrono_timestamps() {
+    use chrono::{DateTime, TimeZone, Utc};
+    use segment::Timestamp;
+
+    let time = Utc.with_ymd_and_hms(1969, 12, 31, 23, 59, 59).unwrap();
+    assert_eq!(time.to_unix_nanos(), Some(-1_000_000_000));
+    assert_eq!(DateTime::<Utc>::from_unix_nanos(-1_000_000_000), Some(time));
+}
+
+#[cfg(feature = "time")]
+#[test]
+fn time_timestamps() {
+    use segment::Timestamp;
+    use time::OffsetDateTime;
+
+    let time = OffsetDateTime::from_unix_timestamp(-1).unwrap();
+    assert_eq!(time.to_unix_nanos(), Some(-1_000_000_000));
+    assert_eq!(OffsetDateTime::from_unix_nanos(-1_000_000_000), Some(time));
+}
@@ -0,0 +1,12 @@
+use segment::Metric;
+
+#[derive(Metric)]
+#[segment(measurement="cpu")]
+struct Cpu {
+    #[segment(measurement)]
+    name: String,
+    #[segment(field)]
+    value: f64,
+}
+
+fn main() {}
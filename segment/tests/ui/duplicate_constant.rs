@@ -0,0 +1,12 @@
+use segment::Metric;
+
+#[derive(Metric)]
+#[segment(measurement="cpu", tag(host = "a"))]
+struct Cpu {
+    #[segment(tag)]
+    host: String,
+    #[segment(field)]
+    value: f64,
+}
+
+fn main() {}
@@ -0,0 +1,23 @@
+use segment::Metric;
+
+#[derive(Metric)]
+#[segment(measurement="cpu")]
+struct Cpu {
+    #[segment(tag)]
+    host: String,
+    #[segment(tag, rename = "host")]
+    hostname: String,
+    #[segment(field)]
+    value: f64,
+}
+
+#[derive(Metric)]
+#[segment(measurement="mem")]
+struct Mem {
+    #[segment(field, rename = "used")]
+    used_bytes: u64,
+    #[segment(field)]
+    used: u64,
+}
+
+fn main() {}
@@ -0,0 +1,16 @@
+use std::time::Duration;
+
+use segment::Metric;
+
+#[derive(Metric)]
+#[segment(measurement="cpu")]
+struct Cpu {
+    #[segment(time)]
+    start: Duration,
+    #[segment(time)]
+    end: Duration,
+    #[segment(field)]
+    value: f64,
+}
+
+fn main() {}
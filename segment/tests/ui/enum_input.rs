@@ -0,0 +1,9 @@
+use segment::Metric;
+
+#[derive(Metric)]
+enum Cpu {
+    Idle,
+    Busy(f64),
+}
+
+fn main() {}
@@ -0,0 +1,18 @@
+use segment::{Flatten, FromLineProto};
+
+#[derive(Flatten)]
+struct Origin {
+    #[segment(tag)]
+    host: String,
+}
+
+#[derive(FromLineProto)]
+#[segment(measurement="cpu")]
+struct Cpu {
+    #[segment(flatten)]
+    origin: Origin,
+    #[segment(field)]
+    value: f64,
+}
+
+fn main() {}
@@ -0,0 +1,13 @@
+use std::time::Duration;
+
+use segment::Flatten;
+
+#[derive(Flatten)]
+struct Origin {
+    #[segment(tag)]
+    host: String,
+    #[segment(time)]
+    timestamp: Duration,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use segment::Metric;
+
+#[derive(Metric)]
+#[segment(measurement="cpu", field(values = [1, 2]))]
+struct Cpu {
+    #[segment(field)]
+    value: f64,
+}
+
+fn main() {}
@@ -0,0 +1,14 @@
+use std::collections::BTreeMap;
+
+use segment::FromLineProto;
+
+#[derive(FromLineProto)]
+#[segment(measurement="cpu")]
+struct Cpu {
+    #[segment(tags)]
+    labels: BTreeMap<String, String>,
+    #[segment(field)]
+    value: f64,
+}
+
+fn main() {}
@@ -0,0 +1,14 @@
+use std::time::Duration;
+
+use segment::Metric;
+
+#[derive(Metric)]
+#[segment(measurement="cpu")]
+struct Cpu {
+    #[segment(time, display)]
+    timestamp: Duration,
+    #[segment(field)]
+    value: f64,
+}
+
+fn main() {}
@@ -0,0 +1,14 @@
+use std::time::Duration;
+
+use segment::Metric;
+
+#[derive(Metric)]
+#[segment(measurement="cpu")]
+struct Cpu {
+    #[segment(time, skip_if = "Option::is_none")]
+    timestamp: Option<Duration>,
+    #[segment(field)]
+    value: f64,
+}
+
+fn main() {}
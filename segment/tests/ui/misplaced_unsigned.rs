@@ -0,0 +1,10 @@
+use segment::Metric;
+
+#[derive(Metric)]
+#[segment(measurement="cpu")]
+struct Cpu {
+    #[segment(field, unsigned)]
+    value: i64,
+}
+
+fn main() {}
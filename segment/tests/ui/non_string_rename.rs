@@ -0,0 +1,10 @@
+use segment::Metric;
+
+#[derive(Metric)]
+#[segment(measurement="cpu")]
+struct Cpu {
+    #[segment(field, rename=42)]
+    value: f64,
+}
+
+fn main() {}
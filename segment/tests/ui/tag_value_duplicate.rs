@@ -0,0 +1,11 @@
+use segment::TagValue;
+
+#[derive(TagValue)]
+enum Status {
+    #[segment(rename = "ok")]
+    Healthy,
+    #[segment(rename = "ok")]
+    Ready,
+}
+
+fn main() {}
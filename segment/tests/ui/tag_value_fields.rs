@@ -0,0 +1,9 @@
+use segment::TagValue;
+
+#[derive(TagValue)]
+enum Status {
+    Ok,
+    Failed(String),
+}
+
+fn main() {}
@@ -0,0 +1,8 @@
+use segment::TagValue;
+
+#[derive(TagValue)]
+struct Status {
+    code: u8,
+}
+
+fn main() {}
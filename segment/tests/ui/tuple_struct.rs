@@ -0,0 +1,6 @@
+use segment::Metric;
+
+#[derive(Metric)]
+struct Cpu(#[segment(field)] f64);
+
+fn main() {}
@@ -0,0 +1,9 @@
+use segment::Metric;
+
+#[derive(Metric)]
+union Cpu {
+    int: u64,
+    float: f64,
+}
+
+fn main() {}